@@ -0,0 +1,115 @@
+//! HTTP/1.1 against HTTP/2 between entry and exit, through an echoing target,
+//! and a single `/stream` request per session against an upload and a download.
+//! `cargo bench --bench transport`
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tcp_over_http::entry::{self, EntryOptions, ExitNode};
+use tcp_over_http::exit::{self, ExitOptions};
+use tcp_over_http::Endpoint;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Sessions opened one after another to measure latency.
+const ROUNDS: u32 = 100;
+/// Sessions transferring at the same time to measure throughput.
+const SESSIONS: usize = 8;
+/// Bytes echoed per session.
+const BYTES: usize = 4 << 20;
+const CHUNK: usize = 64 << 10;
+
+#[tokio::main]
+async fn main() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    tokio::spawn(echo(target));
+    let options = ExitOptions {
+        h2c: true,
+        ..ExitOptions::default()
+    };
+    let (exit_addr, server) = exit::main(
+        &Endpoint::Tcp(vec!["127.0.0.1:0".parse().unwrap()]),
+        Endpoint::Tcp(vec![target_addr]),
+        options,
+    );
+    tokio::spawn(server);
+    let Endpoint::Tcp(exit_addr) = exit_addr else {
+        unreachable!()
+    };
+    let url: reqwest::Url = format!("http://{}/", exit_addr[0]).parse().unwrap();
+
+    let mut results = Vec::new();
+    let modes = [
+        ("HTTP/1.1", false, false),
+        ("HTTP/2", true, false),
+        ("HTTP/2 stream", true, true),
+    ];
+    for (name, http2, stream) in modes {
+        let options = EntryOptions {
+            http2,
+            stream,
+            ..EntryOptions::default()
+        };
+        let exit = Arc::new(ExitNode::new(url.clone(), &options).unwrap());
+        results.push((name, latency(&exit).await, throughput(&exit).await));
+    }
+    println!();
+    for (name, latency, throughput) in results {
+        println!("{name:<13}  open + echo {latency:>10.2?}  {SESSIONS} sessions {throughput:>8.1} MiB/s");
+    }
+}
+
+async fn echo(listener: TcpListener) {
+    loop {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move {
+            let (mut read, mut write) = socket.split();
+            let _ = tokio::io::copy(&mut read, &mut write).await;
+        });
+    }
+}
+
+/// Mean time to open a session and echo a byte through it.
+async fn latency(exit: &Arc<ExitNode>) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        let mut stream = entry::connect(exit.clone()).await.unwrap();
+        stream.write_all(b"x").await.unwrap();
+        stream.read_exact(&mut [0]).await.unwrap();
+    }
+    start.elapsed() / ROUNDS
+}
+
+/// Echoed MiB per second over all sessions.
+async fn throughput(exit: &Arc<ExitNode>) -> f64 {
+    let start = Instant::now();
+    let sessions: Vec<_> = (0..SESSIONS)
+        .map(|_| {
+            let exit = exit.clone();
+            tokio::spawn(async move {
+                let stream = entry::connect(exit).await.unwrap();
+                let (mut read, mut write) = tokio::io::split(stream);
+                let send = async {
+                    let chunk = vec![0; CHUNK];
+                    for _ in 0..BYTES / CHUNK {
+                        write.write_all(&chunk).await.unwrap();
+                    }
+                };
+                let receive = async {
+                    let mut buf = vec![0; CHUNK];
+                    let mut left = BYTES;
+                    while left > 0 {
+                        let len = read.read(&mut buf).await.unwrap();
+                        assert_ne!(len, 0, "session ended early");
+                        left -= len;
+                    }
+                };
+                tokio::join!(send, receive);
+            })
+        })
+        .collect();
+    for x in sessions {
+        x.await.unwrap();
+    }
+    (SESSIONS * BYTES) as f64 / start.elapsed().as_secs_f64() / f64::from(1 << 20)
+}
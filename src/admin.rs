@@ -0,0 +1,322 @@
+use crate::close::CloseReason;
+use crate::exit::ExitSessionManager;
+use crate::tls::{self, TlsClientOptions};
+use crate::{dir_url, join_url, same_secret};
+use actix_web::guard;
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::{get, post, web, HttpResponse, Responder};
+use clap::Subcommand;
+use reqwest::{Client, Method, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use uuid::Uuid;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SessionInfo {
+    pub(crate) id: Uuid,
+    pub(crate) client: Option<SocketAddr>,
+    pub(crate) age_secs: u64,
+    pub(crate) bytes_up: u64,
+    pub(crate) bytes_down: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct StatsInfo {
+    pub(crate) sessions: usize,
+    pub(crate) opened: u64,
+    pub(crate) closed: u64,
+    #[serde(default)]
+    pub(crate) closed_by: BTreeMap<CloseReason, u64>,
+    pub(crate) bytes_up: u64,
+    pub(crate) bytes_down: u64,
+    pub(crate) draining: bool,
+}
+
+#[get("/sessions")]
+async fn list_sessions(manager: web::Data<ExitSessionManager>) -> impl Responder {
+    let guard = manager.sessions.read().await;
+    let mut list = guard
+        .iter()
+        .map(|(uid, sess)| SessionInfo {
+            id: *uid,
+            client: sess.client,
+            age_secs: sess.opened.elapsed().as_secs(),
+            bytes_up: sess.up.bytes.load(Ordering::Relaxed),
+            bytes_down: sess.down.bytes.load(Ordering::Relaxed),
+        })
+        .collect::<Vec<_>>();
+    drop(guard);
+    list.sort_by_key(|x| std::cmp::Reverse(x.age_secs));
+    HttpResponse::Ok().json(list)
+}
+
+#[post("/kill/{uid_s}")]
+async fn kill_session(
+    manager: web::Data<ExitSessionManager>,
+    uid_s: web::Path<String>,
+) -> impl Responder {
+    let Ok(uid) = Uuid::parse_str(&uid_s) else {
+        return HttpResponse::BadRequest().body("invalid session id");
+    };
+    if manager.end(uid, CloseReason::Killed).await {
+        println!("Killed session {uid:#x?}");
+        HttpResponse::Ok().finish()
+    } else {
+        HttpResponse::NotFound().body("session not found")
+    }
+}
+
+#[get("/stats")]
+async fn show_stats(manager: web::Data<ExitSessionManager>) -> impl Responder {
+    let stats = &manager.stats;
+    let closed_by = CloseReason::ALL
+        .into_iter()
+        .map(|x| (x, stats.closed_by[x as usize].load(Ordering::Relaxed)))
+        .filter(|x| x.1 > 0)
+        .collect();
+    HttpResponse::Ok().json(StatsInfo {
+        sessions: manager.sessions.read().await.len(),
+        opened: stats.opened.load(Ordering::Relaxed),
+        closed: stats.closed.load(Ordering::Relaxed),
+        closed_by,
+        bytes_up: stats.bytes_up.load(Ordering::Relaxed),
+        bytes_down: stats.bytes_down.load(Ordering::Relaxed),
+        draining: manager.draining.load(Ordering::Relaxed),
+    })
+}
+
+#[post("/drain")]
+async fn drain(manager: web::Data<ExitSessionManager>) -> impl Responder {
+    manager.draining.store(true, Ordering::Relaxed);
+    println!("Draining, no new sessions are accepted");
+    HttpResponse::Ok().finish()
+}
+
+/// Whether `headers` carry `Authorization: Bearer <token>`.
+pub(crate) fn has_bearer(headers: &HeaderMap, token: &str) -> bool {
+    let Some(value) = headers.get(header::AUTHORIZATION) else {
+        return false;
+    };
+    let Some(given) = value.as_bytes().strip_prefix(b"Bearer ") else {
+        return false;
+    };
+    same_secret(given, token)
+}
+
+/// Requests without the token fall through to the decoy.
+pub(crate) fn configure(cfg: &mut web::ServiceConfig, token: String) {
+    cfg.service(
+        web::scope("/admin")
+            .guard(guard::fn_guard(move |ctx| {
+                has_bearer(ctx.head().headers(), &token)
+            }))
+            .service(list_sessions)
+            .service(kill_session)
+            .service(show_stats)
+            .service(drain),
+    );
+}
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct AdminTarget {
+    /// URL of the exit node.
+    #[clap(short, long, value_parser = |x: &str| Url::parse(x).map(dir_url))]
+    url: Url,
+
+    /// The exit node's --admin-token.
+    #[clap(long, value_name = "TOKEN")]
+    token: String,
+
+    /// Print the JSON returned by the exit node instead of a table.
+    #[clap(long)]
+    json: bool,
+
+    #[clap(flatten)]
+    tls: TlsClientOptions,
+}
+
+impl AdminTarget {
+    fn client(&self) -> Client {
+        let config = match tls::client_config(&self.tls) {
+            Ok(x) => x,
+            Err(x) => {
+                eprintln!("Could not load TLS configuration: {x:#}");
+                std::process::exit(1);
+            }
+        };
+        let builder = Client::builder();
+        let builder = match config {
+            Some(x) => builder.use_preconfigured_tls(x),
+            None => builder,
+        };
+        builder.build().unwrap()
+    }
+
+    /// Exits on transport errors, as there is nothing to retry for a one-off command.
+    async fn send(&self, client: &Client, method: Method, path: &[&str]) -> reqwest::Response {
+        let url = join_url(&self.url, ["admin/"].iter().chain(path).copied());
+        let req = client.request(method, url).bearer_auth(&self.token);
+        match req.send().await {
+            Ok(x) => x,
+            Err(x) => {
+                eprintln!("Could not reach the exit node: {x}");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// The body of a successful response, exits otherwise.
+async fn body(resp: reqwest::Response) -> bytes::Bytes {
+    if resp.status() == StatusCode::NOT_FOUND {
+        eprintln!("{NOT_FOUND}");
+        std::process::exit(1);
+    }
+    if !resp.status().is_success() {
+        eprintln!("Unexpected response: {}", resp.status());
+        std::process::exit(1);
+    }
+    match resp.bytes().await {
+        Ok(x) => x,
+        Err(x) => {
+            eprintln!("Could not read the response: {x}");
+            std::process::exit(1);
+        }
+    }
+}
+
+const NOT_FOUND: &str =
+    "Exit node has no admin endpoints. Was it started with --admin, and is --token right?";
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum AdminCommand {
+    /// List the open sessions.
+    Sessions {
+        #[clap(flatten)]
+        target: AdminTarget,
+    },
+    /// Close a session, as if the entry node had closed it.
+    Kill {
+        session: Uuid,
+
+        #[clap(flatten)]
+        target: AdminTarget,
+    },
+    /// Show counters since the exit node started.
+    Stats {
+        #[clap(flatten)]
+        target: AdminTarget,
+    },
+    /// Stop accepting new sessions and report not ready, e.g. before a restart.
+    Drain {
+        #[clap(flatten)]
+        target: AdminTarget,
+    },
+}
+
+impl AdminCommand {
+    fn target(&self) -> &AdminTarget {
+        match self {
+            Self::Sessions { target }
+            | Self::Kill { target, .. }
+            | Self::Stats { target }
+            | Self::Drain { target } => target,
+        }
+    }
+}
+
+async fn fetch(target: &AdminTarget, client: &Client, path: &str) -> bytes::Bytes {
+    body(target.send(client, Method::GET, &[path]).await).await
+}
+
+fn human_bytes(n: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    #[allow(clippy::cast_precision_loss)]
+    let mut n = n as f64;
+    let mut unit = 0;
+    while n >= 1024.0 && unit < UNITS.len() - 1 {
+        n /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{n} {}", UNITS[unit])
+    } else {
+        format!("{n:.1} {}", UNITS[unit])
+    }
+}
+
+pub async fn main(command: AdminCommand) {
+    let client = command.target().client();
+    match command {
+        AdminCommand::Sessions { target } => {
+            let body = fetch(&target, &client, "sessions").await;
+            if target.json {
+                println!("{}", String::from_utf8_lossy(&body));
+                return;
+            }
+            let list: Vec<SessionInfo> = serde_json::from_slice(&body).unwrap();
+            println!(
+                "{:<32}  {:<40}  {:>8}  {:>10}  {:>10}",
+                "ID", "CLIENT", "AGE", "UP", "DOWN"
+            );
+            for sess in list {
+                let client = sess
+                    .client
+                    .map_or_else(|| "-".to_owned(), |x| x.to_string());
+                println!(
+                    "{:<32}  {:<40}  {:>7}s  {:>10}  {:>10}",
+                    sess.id.simple(),
+                    client,
+                    sess.age_secs,
+                    human_bytes(sess.bytes_up),
+                    human_bytes(sess.bytes_down),
+                );
+            }
+        }
+        AdminCommand::Kill { session, target } => {
+            let path = ["kill/", &session.to_string()];
+            let resp = target.send(&client, Method::POST, &path).await;
+            match resp.status() {
+                StatusCode::OK => println!("Killed session {session:#x?}"),
+                StatusCode::NOT_FOUND => {
+                    eprintln!("Session {session:#x?} not found (or admin endpoints unreachable)");
+                    std::process::exit(1);
+                }
+                status => {
+                    eprintln!("Unexpected response: {status}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        AdminCommand::Stats { target } => {
+            let body = fetch(&target, &client, "stats").await;
+            if target.json {
+                println!("{}", String::from_utf8_lossy(&body));
+                return;
+            }
+            let stats: StatsInfo = serde_json::from_slice(&body).unwrap();
+            println!("sessions    {}", stats.sessions);
+            println!("opened      {}", stats.opened);
+            let by = stats
+                .closed_by
+                .iter()
+                .map(|(reason, n)| format!("{reason} {n}"))
+                .collect::<Vec<_>>();
+            if by.is_empty() {
+                println!("closed      {}", stats.closed);
+            } else {
+                println!("closed      {} ({})", stats.closed, by.join(", "));
+            }
+            println!("bytes up    {}", human_bytes(stats.bytes_up));
+            println!("bytes down  {}", human_bytes(stats.bytes_down));
+            println!("draining    {}", stats.draining);
+        }
+        AdminCommand::Drain { target } => {
+            let resp = target.send(&client, Method::POST, &["drain"]).await;
+            body(resp).await;
+            println!("Exit node is draining");
+        }
+    }
+}
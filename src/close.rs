@@ -0,0 +1,101 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpSocket, TcpStream};
+
+/// Why a session ended. The side which noticed tells the other one,
+/// which closes its connection the same way.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// Both sides closed gracefully.
+    Fin,
+    ClientReset,
+    TargetReset,
+    /// No data for too long.
+    Timeout,
+    /// Through the admin API.
+    Killed,
+}
+
+impl CloseReason {
+    pub(crate) const ALL: [Self; 5] = [
+        Self::Fin,
+        Self::ClientReset,
+        Self::TargetReset,
+        Self::Timeout,
+        Self::Killed,
+    ];
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Fin => "fin",
+            Self::ClientReset => "client_reset",
+            Self::TargetReset => "target_reset",
+            Self::Timeout => "timeout",
+            Self::Killed => "killed",
+        }
+    }
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for CloseReason {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|x| x.as_str() == s)
+            .ok_or_else(|| format!("unknown close reason {s:?}"))
+    }
+}
+
+/// The first reason a session ended for, set wherever that is noticed.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CloseCause(Arc<Mutex<Option<CloseReason>>>);
+
+impl CloseCause {
+    pub(crate) fn set(&self, reason: CloseReason) {
+        self.0.lock().unwrap().get_or_insert(reason);
+    }
+    pub(crate) fn get(&self) -> Option<CloseReason> {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Second handle of a TCP connection, to reset it while its halves are elsewhere.
+#[derive(Debug)]
+pub(crate) struct ResetHandle(TcpSocket);
+
+impl ResetHandle {
+    pub(crate) fn new(stream: TcpStream) -> std::io::Result<(TcpStream, Self)> {
+        let stream = stream.into_std()?;
+        let handle = TcpSocket::from_std_stream(stream.try_clone()?);
+        Ok((TcpStream::from_std(stream)?, Self(handle)))
+    }
+
+    /// Closing the connection sends a RST instead of a FIN then.
+    pub(crate) fn reset(&self) {
+        if let Err(x) = self.0.set_linger(Some(Duration::ZERO)) {
+            dbg!(x, "couldnt reset");
+        }
+    }
+}
+
+#[test]
+fn close_cause() {
+    let cause = CloseCause::default();
+    assert_eq!(cause.get(), None);
+    cause.clone().set(CloseReason::TargetReset);
+    cause.set(CloseReason::Fin);
+    assert_eq!(cause.get(), Some(CloseReason::TargetReset));
+    for x in CloseReason::ALL {
+        assert_eq!(x.to_string().parse(), Ok(x));
+    }
+}
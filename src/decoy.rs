@@ -0,0 +1,269 @@
+use crate::admin;
+use crate::dir_url;
+use crate::exit::{self, drain, ExitOptions};
+use actix_files::NamedFile;
+use actix_web::http::header::{self, HeaderName};
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures::StreamExt;
+use reqwest::{redirect, Client, Url};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug, Default, clap::Args)]
+pub struct DecoyOptions {
+    /// Serve static files from this directory to requests that are not tunnel requests.
+    #[clap(long, value_name = "DIR", conflicts_with = "decoy_url")]
+    pub decoy_dir: Option<PathBuf>,
+
+    /// Forward requests that are not tunnel requests to this website.
+    #[clap(long, value_name = "URL")]
+    pub decoy_url: Option<Url>,
+
+    /// Keep this header from the decoy website as well, e.g. an API key entries send with -H.
+    /// The tunnel token, credentials and cookies are never forwarded.
+    #[clap(long, value_name = "NAME", requires = "decoy_url")]
+    pub decoy_strip_header: Vec<HeaderName>,
+}
+
+/// What scanners and browsers get to see instead of the tunnel.
+#[derive(Debug)]
+pub(crate) struct Decoy {
+    site: Site,
+    /// Requests with one of these come from an entry or the admin CLI, e.g. for a session
+    /// that just ended. They get a plain 404 and never reach the website.
+    tunnel_token: Option<String>,
+    admin_token: Option<String>,
+}
+
+#[derive(Debug)]
+enum Site {
+    NotFound,
+    Dir(PathBuf),
+    Proxy {
+        url: Url,
+        client: Client,
+        strip: Vec<HeaderName>,
+    },
+}
+
+impl Decoy {
+    pub(crate) fn new(options: &ExitOptions) -> Self {
+        let decoy = &options.decoy;
+        let site = if let Some(dir) = &decoy.decoy_dir {
+            Site::Dir(dir.clone())
+        } else if let Some(url) = &decoy.decoy_url {
+            let client = Client::builder()
+                .redirect(redirect::Policy::none())
+                .build()
+                .unwrap();
+            Site::Proxy {
+                url: dir_url(url.clone()),
+                client,
+                strip: decoy.decoy_strip_header.clone(),
+            }
+        } else {
+            Site::NotFound
+        };
+        Self {
+            site,
+            tunnel_token: options.tunnel_token.clone(),
+            admin_token: options.admin_token.clone().filter(|_| options.admin),
+        }
+    }
+
+    fn is_ours(&self, req: &HttpRequest) -> bool {
+        let headers = req.headers();
+        self.tunnel_token
+            .as_ref()
+            .is_some_and(|x| exit::has_tunnel_token(headers, x))
+            || self
+                .admin_token
+                .as_ref()
+                .is_some_and(|x| admin::has_bearer(headers, x))
+    }
+}
+
+/// Requests larger than this are not forwarded to the decoy website.
+const MAX_PROXY_BODY: usize = 1024 * 1024;
+
+/// Maps the request path into `dir`, refusing `..` and hidden files.
+fn static_path(dir: &Path, req_path: &str) -> Option<PathBuf> {
+    let decoded = percent_encoding::percent_decode_str(req_path)
+        .decode_utf8()
+        .ok()?;
+    let mut path = dir.to_path_buf();
+    for segment in decoded.split('/') {
+        if segment.is_empty() {
+            continue;
+        }
+        if segment.starts_with('.') || segment.contains('\\') {
+            return None;
+        }
+        path.push(segment);
+    }
+    if path.is_dir() {
+        path.push("index.html");
+    }
+    Some(path)
+}
+
+fn is_hop_by_hop(name: &header::HeaderName) -> bool {
+    [
+        header::CONNECTION,
+        header::HOST,
+        header::PROXY_AUTHENTICATE,
+        header::PROXY_AUTHORIZATION,
+        header::TE,
+        header::TRAILER,
+        header::TRANSFER_ENCODING,
+        header::UPGRADE,
+    ]
+    .contains(name)
+        || name.as_str() == "keep-alive"
+}
+
+/// Credentials of the tunnel, or of visitors of the website, never forwarded.
+fn is_secret(name: &HeaderName) -> bool {
+    [header::AUTHORIZATION, header::COOKIE].contains(name)
+        || name.as_str() == exit::TUNNEL_TOKEN_HEADER
+}
+
+/// Maps the request path below `base`, `None` if it would leave it, as with `..`.
+/// Never a different host, unlike [`Url::join`] with `//host/` or a whole URL as path.
+fn proxy_url(base: &Url, path: &str, query: &str) -> Option<Url> {
+    let mut url = base.clone();
+    url.set_path(&format!("{}{}", base.path(), path.trim_start_matches('/')));
+    url.set_query(Some(query).filter(|x| !x.is_empty()));
+    url.path().starts_with(base.path()).then_some(url)
+}
+
+/// The request body to forward, or the answer if it is not forwarded.
+async fn read_body(mut payload: web::Payload) -> Result<web::Bytes, HttpResponse> {
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let Ok(chunk) = chunk else {
+            return Err(HttpResponse::BadRequest().finish());
+        };
+        if body.len() + chunk.len() > MAX_PROXY_BODY {
+            return Err(HttpResponse::PayloadTooLarge().finish());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body.freeze())
+}
+
+async fn proxy(
+    url: &Url,
+    client: &Client,
+    strip: &[HeaderName],
+    req: &HttpRequest,
+    body: web::Bytes,
+) -> HttpResponse {
+    let Some(target) = proxy_url(url, req.path(), req.query_string()) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let mut forward = client.request(req.method().clone(), target).body(body);
+    for (name, value) in req.headers() {
+        if !is_hop_by_hop(name) && !is_secret(name) && !strip.contains(name) {
+            forward = forward.header(name, value);
+        }
+    }
+    if let Some(peer) = req.peer_addr() {
+        forward = forward.header("x-forwarded-for", peer.ip().to_string());
+    }
+    let resp = match forward.send().await {
+        Ok(x) => x,
+        Err(x) => {
+            dbg!(x, "decoy website unreachable");
+            return HttpResponse::BadGateway().finish();
+        }
+    };
+    let mut out = HttpResponse::build(resp.status());
+    for (name, value) in resp.headers() {
+        if !is_hop_by_hop(name) {
+            out.append_header((name, value));
+        }
+    }
+    out.streaming(resp.bytes_stream())
+}
+
+/// Answers a request that is not a valid tunnel request.
+pub(crate) async fn respond(
+    decoy: web::Data<Decoy>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> HttpResponse {
+    let body = match &decoy.site {
+        Site::Proxy { .. } if !decoy.is_ours(&req) => match read_body(payload).await {
+            Ok(x) => x,
+            Err(x) => return x,
+        },
+        // e.g. an upload of a session that just ended, the connection is to stay usable
+        _ => {
+            drain(payload).await;
+            web::Bytes::new()
+        }
+    };
+    respond_read(decoy, req, body).await
+}
+
+/// As [`respond`], for a handler which read the body already.
+pub(crate) async fn respond_read(
+    decoy: web::Data<Decoy>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> HttpResponse {
+    if decoy.is_ours(&req) {
+        return HttpResponse::NotFound().finish();
+    }
+    match &decoy.site {
+        Site::NotFound => HttpResponse::NotFound().finish(),
+        Site::Dir(dir) => {
+            let file = static_path(dir, req.path()).and_then(|x| NamedFile::open(x).ok());
+            match file {
+                Some(file) => file.into_response(&req),
+                None => match NamedFile::open(dir.join("404.html")) {
+                    Ok(file) => {
+                        let mut resp = file.into_response(&req);
+                        *resp.status_mut() = actix_web::http::StatusCode::NOT_FOUND;
+                        resp
+                    }
+                    Err(_) => HttpResponse::NotFound().finish(),
+                },
+            }
+        }
+        Site::Proxy { url, client, strip } => proxy(url, client, strip, &req, body).await,
+    }
+}
+
+#[test]
+fn static_paths() {
+    let dir = Path::new("/srv/www");
+    assert_eq!(
+        static_path(dir, "/css/site%20main.css"),
+        Some(dir.join("css/site main.css"))
+    );
+    assert_eq!(static_path(dir, "/a/../../etc/passwd"), None);
+    assert_eq!(static_path(dir, "/a/%2e%2e/b"), None);
+    assert_eq!(static_path(dir, "/.git/config"), None);
+}
+
+#[test]
+fn proxy_urls() {
+    let base = Url::parse("http://site.example/www/").unwrap();
+    let url = |path, query| proxy_url(&base, path, query).map(String::from);
+    assert_eq!(
+        url("/a/b.html", "x=1").as_deref(),
+        Some("http://site.example/www/a/b.html?x=1")
+    );
+    assert_eq!(
+        url("//evil.example/x", "").as_deref(),
+        Some("http://site.example/www/evil.example/x")
+    );
+    assert_eq!(
+        url("/http://169.254.169.254/", "").as_deref(),
+        Some("http://site.example/www/http://169.254.169.254/")
+    );
+    assert_eq!(url("/../x", ""), None);
+    assert_eq!(url("/a/%2e%2e/%2E%2e/x", ""), None);
+    assert_eq!(url("/a\\..\\..\\x", ""), None);
+}
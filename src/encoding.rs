@@ -0,0 +1,279 @@
+// Session bodies as text, for intermediaries which mangle or reject binary ones.
+// Each chunk becomes a line of its own, as proxies do not keep chunk boundaries.
+
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use std::io;
+
+/// How the bodies of a session are encoded, chosen by the entry at `/open`.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum BodyEncoding {
+    #[default]
+    Binary,
+    Base64,
+    Hex,
+    /// Base64 in lines of `{"data":"..."}`.
+    Base64Json,
+    /// Hex in lines of `{"data":"..."}`.
+    HexJson,
+}
+
+/// A line of the JSON encodings.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Line {
+    data: String,
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+fn hex_decode(text: &[u8]) -> Option<Vec<u8>> {
+    let nibble = |x: u8| char::from(x).to_digit(16);
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    text.chunks(2)
+        .map(|x| Some(u8::try_from(nibble(x[0])? << 4 | nibble(x[1])?).unwrap()))
+        .collect()
+}
+
+fn invalid(x: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, x)
+}
+
+impl BodyEncoding {
+    pub(crate) fn content_type(self) -> &'static str {
+        match self {
+            Self::Binary => "application/octet-stream",
+            Self::Base64 | Self::Hex => "text/plain",
+            Self::Base64Json | Self::HexJson => "application/json",
+        }
+    }
+
+    fn text(self, data: &[u8]) -> String {
+        match self {
+            Self::Binary => unreachable!(),
+            Self::Base64 | Self::Base64Json => {
+                base64::engine::general_purpose::STANDARD.encode(data)
+            }
+            Self::Hex | Self::HexJson => data
+                .iter()
+                .flat_map(|x| [HEX[usize::from(x >> 4)], HEX[usize::from(x & 15)]])
+                .map(char::from)
+                .collect(),
+        }
+    }
+
+    /// Bytes of the line encoding `data` bytes, `0` for none.
+    pub(crate) fn encoded_len(self, data: usize) -> usize {
+        if self == Self::Binary || data == 0 {
+            return data;
+        }
+        let text = match self {
+            Self::Base64 | Self::Base64Json => data.div_ceil(3) * 4,
+            _ => data * 2,
+        };
+        text + self.overhead()
+    }
+
+    /// Most bytes which encode to a line of at most `encoded` bytes.
+    pub(crate) fn max_data(self, encoded: usize) -> usize {
+        if self == Self::Binary {
+            return encoded;
+        }
+        let text = encoded.saturating_sub(self.overhead());
+        match self {
+            Self::Base64 | Self::Base64Json => text / 4 * 3,
+            _ => text / 2,
+        }
+    }
+
+    /// The newline, and `{"data":""}` of the JSON encodings.
+    fn overhead(self) -> usize {
+        match self {
+            Self::Base64Json | Self::HexJson => 12,
+            _ => 1,
+        }
+    }
+
+    pub(crate) fn encode(self, data: Bytes) -> Bytes {
+        if self == Self::Binary || data.is_empty() {
+            return data;
+        }
+        let text = self.text(&data);
+        let mut line = match self {
+            Self::Base64Json | Self::HexJson => serde_json::to_vec(&Line { data: text }).unwrap(),
+            _ => text.into_bytes(),
+        };
+        line.push(b'\n');
+        line.into()
+    }
+
+    fn decode_line(self, line: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        let json;
+        let text = match self {
+            Self::Base64Json | Self::HexJson => {
+                json = serde_json::from_slice::<Line>(line).map_err(invalid)?;
+                json.data.as_bytes()
+            }
+            _ => line,
+        };
+        match self {
+            Self::Binary => unreachable!(),
+            Self::Base64 | Self::Base64Json => base64::engine::general_purpose::STANDARD
+                .decode_vec(text, out)
+                .map_err(invalid),
+            Self::Hex | Self::HexJson => {
+                out.extend(hex_decode(text).ok_or_else(|| invalid("invalid hex"))?);
+                Ok(())
+            }
+        }
+    }
+
+    /// A whole body at once.
+    pub(crate) fn decode(self, body: Bytes) -> io::Result<Bytes> {
+        let mut decoder = Decoder::new(self);
+        let data = decoder.decode(body)?;
+        decoder.finish()?;
+        Ok(data)
+    }
+}
+
+/// Longest line a [`Decoder`] buffers. Senders encode chunks of a socket read or datagram,
+/// far shorter than this.
+const MAX_LINE: usize = 1024 * 1024;
+
+/// Decodes a body chunk by chunk, however the lines are split.
+#[derive(Debug)]
+pub(crate) struct Decoder {
+    encoding: BodyEncoding,
+    partial: BytesMut,
+}
+
+impl Decoder {
+    pub(crate) fn new(encoding: BodyEncoding) -> Self {
+        Self {
+            encoding,
+            partial: BytesMut::new(),
+        }
+    }
+
+    /// The data of all lines completed by `chunk`.
+    pub(crate) fn decode(&mut self, chunk: Bytes) -> io::Result<Bytes> {
+        if self.encoding == BodyEncoding::Binary {
+            return Ok(chunk);
+        }
+        self.partial.extend_from_slice(&chunk);
+        let lines = match self.partial.iter().rposition(|&x| x == b'\n') {
+            Some(end) => self.partial.split_to(end + 1),
+            None => BytesMut::new(),
+        };
+        if self.partial.len() > MAX_LINE {
+            return Err(invalid("line too long"));
+        }
+        let mut out = Vec::new();
+        for line in lines[..].split(|&x| x == b'\n') {
+            let line = line.trim_ascii();
+            if !line.is_empty() {
+                self.encoding.decode_line(line, &mut out)?;
+            }
+        }
+        Ok(out.into())
+    }
+
+    /// Fails if the body ended within a line.
+    pub(crate) fn finish(&self) -> io::Result<()> {
+        match self.partial.trim_ascii() {
+            [] => Ok(()),
+            _ => Err(invalid("body ends within a line")),
+        }
+    }
+}
+
+/// Decodes `body`, ending with an error instead if it is not valid.
+pub(crate) fn decode_stream<S, E>(
+    encoding: BodyEncoding,
+    body: S,
+) -> impl Stream<Item = io::Result<Bytes>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let state = Some((Box::pin(body), Decoder::new(encoding)));
+    futures::stream::unfold(state, |state| async move {
+        let (mut body, mut decoder) = state?;
+        loop {
+            let chunk = match body.next().await {
+                Some(Ok(x)) => x,
+                Some(Err(x)) => return Some((Err(io::Error::other(x)), None)),
+                None => return decoder.finish().err().map(|x| (Err(x), None)),
+            };
+            match decoder.decode(chunk) {
+                Ok(x) if x.is_empty() => {}
+                Ok(x) => return Some((Ok(x), Some((body, decoder)))),
+                Err(x) => return Some((Err(x), None)),
+            }
+        }
+    })
+}
+
+#[test]
+fn roundtrip() {
+    use clap::ValueEnum;
+
+    let data = (0..=255).collect::<Vec<u8>>();
+    for &encoding in BodyEncoding::value_variants() {
+        let body = [
+            encoding.encode(Bytes::from_static(b"hello")),
+            encoding.encode(Bytes::new()),
+            encoding.encode(data.clone().into()),
+        ]
+        .concat();
+        assert_eq!(
+            encoding.decode(body.clone().into()).unwrap(),
+            [&b"hello"[..], &data].concat()
+        );
+        // split anywhere
+        let mut decoder = Decoder::new(encoding);
+        let mut out = Vec::new();
+        for x in body.chunks(3) {
+            out.extend(decoder.decode(Bytes::copy_from_slice(x)).unwrap());
+        }
+        decoder.finish().unwrap();
+        assert_eq!(out, [&b"hello"[..], &data].concat());
+    }
+    assert_eq!(
+        &BodyEncoding::Hex.encode(Bytes::from_static(b"\x01\xab"))[..],
+        b"01ab\n"
+    );
+    assert_eq!(
+        &BodyEncoding::Base64Json.encode(Bytes::from_static(b"hi"))[..],
+        b"{\"data\":\"aGk=\"}\n"
+    );
+    assert!(BodyEncoding::Hex
+        .decode(Bytes::from_static(b"0g\n"))
+        .is_err());
+    assert!(BodyEncoding::Base64
+        .decode(Bytes::from_static(b"aGk="))
+        .is_err());
+
+    // not buffered without end
+    let mut decoder = Decoder::new(BodyEncoding::Hex);
+    let chunk = Bytes::from(vec![b'0'; 64 * 1024]);
+    let err = (0..=MAX_LINE / chunk.len())
+        .map(|_| decoder.decode(chunk.clone()))
+        .find_map(Result::err)
+        .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
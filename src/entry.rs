@@ -1,158 +1,962 @@
+use crate::close::{CloseCause, CloseReason, ResetHandle};
+use crate::encoding;
 use crate::error::Trace;
+use crate::limit::ByteSize;
 use crate::ouroboros_impl_wrapper::WrapperBuilder;
-use crate::{artex, join_url};
+use crate::pool::{self, SessionPool};
+#[cfg(unix)]
+use crate::remove_stale_socket;
+use crate::tls;
+use crate::udp;
+use crate::upstream;
+use crate::{artex, dir_url, join_url, Artex, Endpoint};
 
-use bytes::Bytes;
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use futures::future::Either;
 use futures::Future;
+use halfbrown::HashMap as Map;
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
 use reqwest::{Body, Client, Response, Url};
 use std::convert::{identity, Infallible, TryInto};
 use std::io::ErrorKind;
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 use stream_cancel::Valve;
-use tokio::net::TcpListener;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::{mpsc, oneshot};
 use tokio_stream::StreamExt;
 use tokio_util::codec::{BytesCodec, FramedRead};
 use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-lazy_static::lazy_static! {
-    pub(crate) static ref CLIENT: Client = Client::new();
+pub use crate::encoding::BodyEncoding;
+pub use crate::tls::{Pin as TlsPin, TlsClientOptions};
+pub use crate::upstream::{NoProxy, UpstreamProxyOptions};
+
+#[derive(Clone, Debug, Default, clap::Args)]
+pub struct EntryOptions {
+    /// Listen even if the exit node does not answer its health check.
+    #[clap(long)]
+    pub skip_health_check: bool,
+
+    #[clap(flatten)]
+    pub tls: TlsClientOptions,
+
+    #[clap(flatten)]
+    pub upstream: UpstreamProxyOptions,
+
+    /// Speak HTTP/2 to the exit, so all sessions share a single connection. A plaintext exit
+    /// needs --h2c for this, over TLS it is negotiated.
+    #[clap(long)]
+    pub http2: bool,
+
+    /// Carry both directions of a session in one `/stream` request instead of an upload and a
+    /// download. Every hop to the exit has to pass the response on while the request body is
+    /// still streaming, as HTTP/2 does. Over HTTP/1.1, a FIN of the target reaches the client
+    /// only once the client is done sending as well.
+    #[clap(long)]
+    pub stream: bool,
+
+    /// Encoding of the session bodies in both directions, as text for intermediaries which
+    /// mangle or reject binary bodies. The JSON variants send a `{"data":"..."}` line per chunk.
+    #[clap(long, value_name = "ENCODING", default_value = "binary")]
+    pub body_encoding: BodyEncoding,
+
+    /// Upload in requests with bodies of at most this many bytes each, after `--body-encoding`,
+    /// for proxies which limit the body size, like nginx's `client_max_body_size`. The exit
+    /// keeps their order.
+    #[clap(long, value_name = "BYTES", conflicts_with = "stream")]
+    pub upload_part_size: Option<ByteSize>,
+
+    /// End each download response after about this many bytes and go on in a new request.
+    #[clap(long, value_name = "BYTES", conflicts_with = "stream")]
+    pub download_part_size: Option<ByteSize>,
+
+    /// End each download response after this many seconds and go on in a new request,
+    /// for proxies with response timeouts.
+    #[clap(long, value_name = "SECS", conflicts_with = "stream")]
+    pub download_part_secs: Option<u64>,
+
+    /// Initial HTTP/2 window of each stream, the bytes the exit may send ahead per download.
+    /// Uploads are limited by the exit's windows instead, see its options of the same name.
+    #[clap(long, value_name = "BYTES")]
+    pub http2_stream_window: Option<ByteSize>,
+
+    /// Initial HTTP/2 window of the connection, shared by all downloads.
+    #[clap(long, value_name = "BYTES")]
+    pub http2_connection_window: Option<ByteSize>,
+
+    /// Extra header for every request to the exit node, e.g. `X-Api-Key: secret`.
+    #[clap(short = 'H', long = "header", value_name = "NAME: VALUE")]
+    pub headers: Vec<HeaderArg>,
+
+    /// Host header to send instead of the host of --target-url, for virtual hosts.
+    #[clap(long, value_name = "HOST")]
+    pub host_header: Option<HeaderValue>,
+
+    /// HTTP Basic credentials for the exit node (or the reverse proxy in front of it).
+    #[clap(long, value_name = "USER:PASSWORD")]
+    pub basic_auth: Option<String>,
+
+    /// User-Agent header of every request.
+    #[clap(long, value_name = "AGENT")]
+    pub user_agent: Option<HeaderValue>,
+
+    /// Open sessions with what a new client sends right away, and have the exit wait up to
+    /// this many milliseconds for the target's first bytes to answer with, saving round trips
+    /// for request/response protocols. A client sending nothing at first is not waited for.
+    #[clap(long, value_name = "MS")]
+    pub early_data: Option<u64>,
+
+    /// Keep this many sessions open ahead of clients, each already connected to the target,
+    /// so accepted clients are bridged right away. Pooled sessions can not pass on the
+    /// client's address and skip --early-data.
+    #[clap(long, value_name = "N", default_value_t = 0)]
+    pub pool: usize,
+
+    /// Seconds after which an unused pooled session is closed and replaced.
+    /// 0 keeps it open.
+    #[clap(long, value_name = "SECS", default_value_t = 30)]
+    pub pool_idle: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct HeaderArg(HeaderName, HeaderValue);
+
+impl FromStr for HeaderArg {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s
+            .split_once(':')
+            .ok_or_else(|| format!("{s:?}: expected NAME: VALUE"))?;
+        Ok(Self(
+            name.trim().parse().map_err(|x| format!("{name:?}: {x}"))?,
+            value
+                .trim()
+                .parse()
+                .map_err(|x| format!("{value:?}: {x}"))?,
+        ))
+    }
 }
 
-async fn close_session(target: &Url, uid: Uuid) {
-    let resp = CLIENT
-        .get(join_url(target, ["close/", &uid.to_string()]))
+/// The exit node and the HTTP client configured to reach it.
+#[derive(Debug)]
+pub struct ExitNode {
+    pub(crate) url: Url,
+    client: Client,
+    early_data: Option<Duration>,
+    stream: bool,
+    encoding: BodyEncoding,
+    upload_part_size: Option<usize>,
+    /// Query of the download requests, empty for a single one.
+    download_parts: Vec<(&'static str, u64)>,
+    pub(crate) pool: Option<SessionPool>,
+}
+
+impl ExitNode {
+    /// # Errors
+    /// If a file of the TLS options can not be loaded or a header is invalid.
+    pub fn new(url: Url, options: &EntryOptions) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            ![options.upload_part_size, options.download_part_size].contains(&Some(ByteSize(0)))
+                && options.download_part_secs != Some(0),
+            "parts can not be empty"
+        );
+        if let Some(size) = options.upload_part_size {
+            anyhow::ensure!(
+                options.body_encoding.max_data(size.0.try_into()?) > 0,
+                "--upload-part-size is too small for --body-encoding"
+            );
+        }
+        let mut builder = Client::builder();
+        if let Some(mut tls) = tls::client_config(&options.tls)? {
+            if options.http2 {
+                tls.alpn_protocols = vec![b"h2".to_vec()];
+            }
+            builder = builder.use_preconfigured_tls(tls);
+        }
+        if options.http2 {
+            builder = builder.http2_prior_knowledge();
+        }
+        if let Some(size) = options.http2_stream_window {
+            builder = builder.http2_initial_stream_window_size(http2_window(size)?);
+        }
+        if let Some(size) = options.http2_connection_window {
+            builder = builder.http2_initial_connection_window_size(http2_window(size)?);
+        }
+        let mut headers = HeaderMap::new();
+        for HeaderArg(name, value) in &options.headers {
+            headers.append(name, value.clone());
+        }
+        if let Some(host) = &options.host_header {
+            headers.insert(header::HOST, host.clone());
+        }
+        if let Some(auth) = &options.basic_auth {
+            let (user, password) = auth.split_once(':').unwrap_or((auth, ""));
+            let encoded =
+                base64::engine::general_purpose::STANDARD.encode(format!("{user}:{password}"));
+            let mut value = HeaderValue::from_str(&format!("Basic {encoded}"))?;
+            value.set_sensitive(true);
+            headers.insert(header::AUTHORIZATION, value);
+        }
+        if let Some(agent) = &options.user_agent {
+            headers.insert(header::USER_AGENT, agent.clone());
+        }
+        builder = builder.default_headers(headers);
+        if let Some(proxy) = upstream::proxy(&options.upstream) {
+            builder = builder.no_proxy().proxy(proxy);
+        } else if options.upstream.no_env_proxy {
+            builder = builder.no_proxy();
+        }
+        Ok(Self {
+            url: dir_url(url),
+            client: builder.build()?,
+            early_data: options.early_data.map(Duration::from_millis),
+            stream: options.stream,
+            encoding: options.body_encoding,
+            upload_part_size: options.upload_part_size.map(|x| x.0.try_into().unwrap()),
+            download_parts: [
+                options.download_part_size.map(|x| ("size", x.0)),
+                options.download_part_secs.map(|x| ("secs", x)),
+            ]
+            .into_iter()
+            .flatten()
+            .collect(),
+            pool: (options.pool > 0).then(|| {
+                let idle = (options.pool_idle > 0).then(|| Duration::from_secs(options.pool_idle));
+                SessionPool::new(options.pool, idle)
+            }),
+        })
+    }
+}
+
+pub(crate) fn http2_window(size: ByteSize) -> anyhow::Result<u32> {
+    u32::try_from(size.0)
+        .ok()
+        .filter(|&x| x < 1 << 31)
+        .ok_or_else(|| anyhow::anyhow!("HTTP/2 window of {} bytes is above 2G", size.0))
+}
+
+/// Whether the exit answers its health check, and why not.
+pub(crate) async fn check_exit(exit: &ExitNode) -> Result<(), String> {
+    let target = &exit.url;
+    match exit.client.get(join_url(target, ["healthz"])).send().await {
+        Ok(resp) if resp.status() == reqwest::StatusCode::OK => Ok(()),
+        Ok(resp) => Err(format!(
+            "Exit node {target} is not healthy: {}",
+            resp.status()
+        )),
+        Err(e) => Err(format!("Exit node {target} is not reachable: {e}")),
+    }
+}
+
+/// Tells the exit how the session ended here, if known,
+/// and returns how it ended there. `None` if the exit forgot the session.
+pub(crate) async fn close_session(
+    exit: &ExitNode,
+    uid: Uuid,
+    reason: Option<CloseReason>,
+) -> Option<CloseReason> {
+    let mut req = exit
+        .client
+        .get(join_url(&exit.url, ["close/", &uid.to_string()]));
+    if let Some(reason) = reason {
+        req = req.query(&[("reason", reason.as_str())]);
+    }
+    match req.send().await {
+        Ok(resp) if resp.status() == reqwest::StatusCode::OK => {
+            resp.text().await.ok()?.parse().ok()
+        }
+        x => {
+            drop(dbg!(x));
+            None
+        }
+    }
+}
+
+/// Cancels `stop` once the exit ended the session, except after a FIN,
+/// which lets both directions finish on their own.
+async fn wait_session(exit: Arc<ExitNode>, uid: Uuid, stop: CancellationToken) {
+    let resp = exit
+        .client
+        .get(join_url(&exit.url, ["wait/", &uid.to_string()]))
         .send()
-        .await
-        .unwrap();
-    assert_ok(resp).await;
-}
-async fn init_http_session(target: &Url) -> Trace<Uuid> {
-    let resp = CLIENT.get(join_url(target, ["open"])).send().await.unwrap();
-    let resp = assert_ok(resp).await;
-    return Ok(Uuid::from_bytes(
-        match identity::<&[u8]>(&resp.bytes().await.unwrap()).try_into() {
+        .await;
+    match resp {
+        Ok(x) if x.status() == reqwest::StatusCode::OK => {
+            // anything else is not the exit's answer, e.g. a proxy's error page
+            match x.text().await.ok().and_then(|x| x.parse().ok()) {
+                Some(CloseReason::Fin) | None => {}
+                Some(_) => stop.cancel(),
+            }
+        }
+        // an exit without `/wait`, the transfers notice anyway
+        x => drop(dbg!(x)),
+    }
+}
+
+/// How often the entry asks again when the exit refuses a session with a Retry-After.
+const OPEN_RETRIES: usize = 3;
+/// Longer waits fail the session instead, as the client is waiting for it.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// The delay of a Retry-After header in seconds. HTTP dates are not worth supporting here.
+fn retry_after(resp: &reqwest::Response) -> Option<Duration> {
+    let value = resp.headers().get(header::RETRY_AFTER)?;
+    value
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// `peer` is the accepted connection's (remote, local) address,
+/// for the exit to pass on to the target.
+/// `early` is what the client sent already, with how long the exit is to wait for an answer.
+/// Returns the session with the target's first bytes.
+pub(crate) async fn init_http_session(
+    exit: &ExitNode,
+    peer: Option<(SocketAddr, SocketAddr)>,
+    early: Option<(Vec<u8>, Duration)>,
+) -> Trace<(Uuid, Bytes)> {
+    let url = join_url(&exit.url, ["open"]);
+    let mut req = match early {
+        Some((data, wait)) => exit
+            .client
+            .post(url)
+            .query(&[("wait", wait.as_millis())])
+            .header(header::CONTENT_TYPE, exit.encoding.content_type())
+            .body(exit.encoding.encode(data.into())),
+        None => exit.client.get(url),
+    };
+    if let Some((src, dst)) = peer {
+        req = req.query(&[("src", src), ("dst", dst)]);
+    }
+    if exit.encoding != BodyEncoding::Binary {
+        req = req.query(&[("encoding", exit.encoding)]);
+    }
+    let mut retries = OPEN_RETRIES;
+    let resp = loop {
+        let resp = match req.try_clone().unwrap().send().await {
+            Ok(x) => x,
+            Err(x) => {
+                use crate::error::ContextExt;
+                return Err(x.to_string().with_context("exit unreachable"));
+            }
+        };
+        if ![
+            reqwest::StatusCode::TOO_MANY_REQUESTS,
+            reqwest::StatusCode::SERVICE_UNAVAILABLE,
+        ]
+        .contains(&resp.status())
+        {
+            break resp;
+        }
+        match retry_after(&resp) {
+            Some(wait) if retries > 0 && wait <= MAX_RETRY_AFTER => {
+                retries -= 1;
+                tokio::time::sleep(wait).await;
+            }
+            _ => {
+                use crate::error::ContextExt;
+                return Err(resp.headers().clone().with_context("exit refused session"));
+            }
+        }
+    };
+    if resp.status() != reqwest::StatusCode::OK {
+        use crate::error::ContextExt;
+        let status = resp.status();
+        let body = resp.text().await.unwrap_or_default();
+        return Err(body.with_context(format!("exit answered {status}")));
+    }
+    let content_type = resp.headers().get(header::CONTENT_TYPE).cloned();
+    let body = resp.bytes().await.unwrap();
+    // an exit without encodings answers in binary
+    let expected = Some(exit.encoding.content_type().as_bytes());
+    if exit.encoding != BodyEncoding::Binary
+        && !body.is_empty()
+        && content_type.as_ref().map(HeaderValue::as_bytes) != expected
+    {
+        use crate::error::ContextExt;
+        return Err(content_type.with_context("exit does not support the body encoding"));
+    }
+    let mut body = match exit.encoding.decode(body) {
+        Ok(x) => x,
+        Err(x) => {
+            use crate::error::ContextExt;
+            return Err(x.to_string().with_context("invalid open response"));
+        }
+    };
+    // the target's first bytes follow
+    let early = body.split_off(body.len().min(16));
+    return Ok((
+        Uuid::from_bytes(match identity::<&[u8]>(&body).try_into() {
             Ok(x) => x,
             Err(x) => {
                 use crate::error::ContextExt;
                 //signal from exit
                 return Err(x.with_context("couldnt connect to target"));
             }
-        },
+        }),
+        early,
     ));
 }
 
-async fn upload_req<S>(target: &Url, uid: Uuid, data: S)
+/// Most bytes sent along with opening a session.
+const EARLY_DATA_MAX: usize = 16 * 1024;
+
+/// How long a new client has to start sending. Clients of server-first protocols, like SMTP,
+/// send nothing before the banner, and their session is opened without waiting for them.
+const EARLY_DATA_START: Duration = Duration::from_millis(20);
+
+/// What the client sends right after connecting, within `wait` at most.
+async fn read_early<S: AsyncRead + Unpin>(socket: &mut S, wait: Duration) -> Vec<u8> {
+    let mut buf = vec![0; EARLY_DATA_MAX];
+    let wait = wait.min(EARLY_DATA_START);
+    let len = match tokio::time::timeout(wait, socket.read(&mut buf)).await {
+        Ok(Ok(x)) => x,
+        // noticed again by the transfer
+        Ok(Err(x)) => {
+            dbg!(x);
+            0
+        }
+        Err(_) => 0,
+    };
+    buf.truncate(len);
+    buf
+}
+
+/// `true` if all data reached the target, which got a FIN then.
+/// In parts with `--upload-part-size`, the last one empty.
+async fn upload_body<S>(exit: &ExitNode, uid: Uuid, data: S) -> bool
+where
+    S: futures::Stream<Item = Result<BytesMut, anyhow::Error>> + Send + Sync + 'static,
+{
+    let Some(size) = exit.upload_part_size else {
+        return upload_req(exit, uid, None, data).await;
+    };
+    // the client's body pends on errors
+    let mut data = Box::pin(data.map_while(Result::ok)).fuse();
+    let mut rest = Bytes::new();
+    let mut seq = 0;
+    loop {
+        if rest.is_empty() {
+            let Some(next) = futures::StreamExt::next(&mut data).await else {
+                let empty = futures::stream::empty::<Result<Bytes, Infallible>>();
+                return upload_req(exit, uid, Some((seq, true)), empty).await;
+            };
+            rest = next.freeze();
+        }
+        let (tx, rx) = mpsc::channel(1);
+        let feed = async {
+            let tx = tx;
+            // in encoded bytes, as each chunk becomes a line of its own
+            let mut left = size;
+            loop {
+                let part = rest.split_to(exit.encoding.max_data(left).min(rest.len()));
+                left -= exit.encoding.encoded_len(part.len());
+                if tx.send(Ok::<_, Infallible>(part)).await.is_err()
+                    || exit.encoding.max_data(left) == 0
+                {
+                    break;
+                }
+                match futures::StreamExt::next(&mut data).await {
+                    Some(x) => rest = x.freeze(),
+                    None => break,
+                }
+            }
+        };
+        let part = tokio_stream::wrappers::ReceiverStream::new(rx);
+        let (received, ()) = tokio::join!(upload_req(exit, uid, Some((seq, false)), part), feed);
+        if !received {
+            return false;
+        }
+        seq += 1;
+    }
+}
+
+/// `part` is the sequence number, and if it is the last one. `true` if the exit took all
+/// of it, for the whole body or the last part once the target got a FIN.
+async fn upload_req<S>(exit: &ExitNode, uid: Uuid, part: Option<(u64, bool)>, data: S) -> bool
 where
     S: futures::TryStream + Send + Sync + 'static,
     S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
-    bytes::Bytes: From<S::Ok>,
+    S::Ok: Into<Bytes>,
 {
-    let resp = CLIENT
-        .post(join_url(target, ["upload/", &uid.to_string()]))
-        .body(Body::wrap_stream(data))
+    use futures::TryStreamExt;
+
+    let encoding = exit.encoding;
+    let data = data.map_ok(move |x| encoding.encode(x.into()));
+    let mut req = exit
+        .client
+        .post(join_url(&exit.url, ["upload/", &uid.to_string()]))
+        .header(header::CONTENT_TYPE, encoding.content_type());
+    if let Some((seq, fin)) = part {
+        req = req.query(&[("seq", seq)]).query(&[("fin", fin)]);
+    }
+    let resp = match req.body(Body::wrap_stream(data)).send().await {
+        Ok(x) if x.status() == reqwest::StatusCode::OK => x,
+        // the exit ended the session already, `/close` tells why
+        x => {
+            drop(dbg!(x));
+            return false;
+        }
+    };
+    let expected = match part {
+        Some((_, false)) => "received",
+        _ => "finished",
+    };
+    dbg!(resp.text().await).is_ok_and(|x| x == expected)
+}
+
+/// `Ok(None)` once a download part answers that the target ended in the one before,
+/// `Err` if the exit ended the session already.
+async fn download_req(exit: &ExitNode, uid: Uuid) -> Result<Option<Response>, ()> {
+    let resp = exit
+        .client
+        .get(join_url(&exit.url, ["download/", &uid.to_string()]))
+        .query(&exit.download_parts)
         .send()
-        .await
-        .unwrap();
-    let resp = assert_ok(resp).await;
-    dbg!(resp.text().await.unwrap());
+        .await;
+    match resp {
+        Ok(x) if x.status() == reqwest::StatusCode::OK => Ok(Some(x)),
+        Ok(x) if x.status() == reqwest::StatusCode::GONE && !exit.download_parts.is_empty() => {
+            Ok(None)
+        }
+        x => {
+            drop(dbg!(x));
+            Err(())
+        }
+    }
+}
+
+/// Copies what the target sends to the client, in parts with `--download-part-*`.
+/// `true` if it all arrived.
+async fn download_body<W>(
+    exit: &ExitNode,
+    uid: Uuid,
+    s_write: &mut W,
+    stop: &CancellationToken,
+    cause: &CloseCause,
+) -> bool
+where
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let resp = match download_req(exit, uid).await {
+            Ok(Some(x)) => x,
+            Ok(None) => return true,
+            Err(()) => return false,
+        };
+        if !copy_down(resp, exit.encoding, s_write, stop, cause).await {
+            return false;
+        }
+        if exit.download_parts.is_empty() {
+            return true;
+        }
+    }
 }
 
-async fn download_req(
-    target: &Url,
+/// Both directions in one request. The response, what the target sends, goes to `response`
+/// as soon as it starts. `true` once all data is sent, the exit ends the response only
+/// after it reached the target.
+async fn stream_req<S>(
+    exit: &ExitNode,
     uid: Uuid,
-) -> impl futures::Stream<Item = reqwest::Result<Bytes>> {
-    let resp = CLIENT
-        .get(join_url(target, ["download/", &uid.to_string()]))
+    data: S,
+    response: oneshot::Sender<Response>,
+) -> bool
+where
+    S: futures::TryStream + Send + Sync + 'static,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    S::Ok: Into<Bytes>,
+{
+    use futures::TryStreamExt;
+
+    let encoding = exit.encoding;
+    let sent = CancellationToken::new();
+    let data = {
+        let sent = sent.clone();
+        data.map_ok(move |x| encoding.encode(x.into()))
+            .chain(futures::stream::poll_fn(move |_| {
+                sent.cancel();
+                Poll::Ready(None)
+            }))
+    };
+    let resp = exit
+        .client
+        .post(join_url(&exit.url, ["stream/", &uid.to_string()]))
+        .header(header::CONTENT_TYPE, encoding.content_type())
+        .body(Body::wrap_stream(data))
         .send()
-        .await
-        .unwrap();
-    let resp = assert_ok(resp).await;
-    return resp.bytes_stream();
+        .await;
+    let resp = match resp {
+        Ok(x) if x.status() == reqwest::StatusCode::OK => x,
+        // as in `upload_req`, the download gives up with the dropped sender
+        x => {
+            drop(dbg!(x));
+            return false;
+        }
+    };
+    // the download may have given up already
+    let _ = response.send(resp);
+    sent.cancelled().await;
+    true
 }
 
-async fn process_socket(target_url: Arc<Url>, socket: tokio::net::TcpStream) -> Trace<Uuid> {
-    let uid = init_http_session(&target_url).await?;
+/// `peer` as in [`init_http_session`], `reset` as in [`transfer`].
+/// Takes a pooled session if there is one.
+pub(crate) async fn process_socket<S>(
+    exit: Arc<ExitNode>,
+    mut socket: S,
+    peer: Option<(SocketAddr, SocketAddr)>,
+    reset: Option<ResetHandle>,
+) -> Trace<Uuid>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
+{
+    if let Some(uid) = exit.pool.as_ref().and_then(SessionPool::take) {
+        println!("HTTP Server copies. Took pooled session {uid:#x?}");
+        transfer(exit, uid, socket, reset).await;
+        return Ok(uid);
+    }
+    let early = match exit.early_data {
+        Some(wait) => Some((read_early(&mut socket, wait).await, wait)),
+        None => None,
+    };
+    let (uid, early) = init_http_session(&exit, peer, early).await?;
     println!("HTTP Server copies. Established session {uid:#x?}");
+    if let Err(x) = socket.write_all(&early).await {
+        dbg!(x);
+    }
+    transfer(exit, uid, socket, reset).await;
+    return Ok(uid);
+}
+
+/// What the client sends, for the upload body. After a read error it cancels `failed`
+/// and stays pending, as ending the body would pass on a FIN.
+fn client_body<R>(
+    s_read: &Artex<R>,
+    failed: CancellationToken,
+) -> impl futures::Stream<Item = Result<BytesMut, anyhow::Error>> + Send + Sync
+where
+    R: AsyncRead + Unpin + Send + Sync + 'static,
+{
+    let stream = WrapperBuilder {
+        guard: s_read.clone().try_lock_owned().unwrap(),
+        fr_builder: |a| FramedRead::new(a, BytesCodec::new()),
+    }
+    .build();
+    let held = failed.clone();
+    stream
+        .map_while(move |x| match x {
+            Ok(x) => Some(Ok(x)),
+            Err(x) => {
+                dbg!(x);
+                failed.cancel();
+                None
+            }
+        })
+        .chain(futures::stream::poll_fn(move |_| {
+            if held.is_cancelled() {
+                Poll::Pending
+            } else {
+                Poll::Ready(None)
+            }
+        }))
+}
 
-    let (s_read, mut s_write) = socket.into_split();
+/// Copies a response of the target's data to the client, until `stop`.
+/// `true` if it all arrived.
+async fn copy_down<W>(
+    resp: Response,
+    encoding: BodyEncoding,
+    s_write: &mut W,
+    stop: &CancellationToken,
+    cause: &CloseCause,
+) -> bool
+where
+    W: AsyncWrite + Unpin,
+{
+    use futures::TryStreamExt;
+    use tokio_util::compat::FuturesAsyncReadCompatExt;
 
+    let mut failed = false;
+    let mut r = Box::pin(encoding::decode_stream(encoding, resp.bytes_stream()))
+        .map_while(|x| match x {
+            Ok(x) => Some(Ok(x)),
+            Err(x) => {
+                dbg!(x);
+                failed = true;
+                None
+            }
+        })
+        .into_async_read()
+        .compat();
+
+    let finished = tokio::select! {
+        x = tokio::io::copy(&mut r, s_write) => match x {
+            Ok(_) => true,
+            Err(x) => {
+                dbg!(x);
+                cause.set(CloseReason::ClientReset);
+                false
+            }
+        },
+        () = stop.cancelled() => false,
+    };
+    drop(r);
+    finished && !failed
+}
+
+/// Copies between `socket` and the session until one side is done, then closes the session.
+/// If the target was reset or the session killed, `reset` resets the client connection.
+async fn transfer<S>(exit: Arc<ExitNode>, uid: Uuid, socket: S, reset: Option<ResetHandle>)
+where
+    S: AsyncRead + AsyncWrite + Send + Sync + 'static,
+{
+    let (s_read, mut s_write) = tokio::io::split(socket);
+    let cause = CloseCause::default();
+    // how the exit saw it end, if it was told early
+    let closed = CloseCause::default();
+
+    // A FIN is passed on and the other direction keeps flowing,
+    // anything else tears down both.
     let stop_download = CancellationToken::new();
     let (stop_upload, valve) = Valve::new();
+    // with `/stream`, the upload request's response is the download
+    let (response_tx, response_rx) = oneshot::channel();
+    let mut response_tx = exit.stream.then_some(response_tx);
+    let mut response_rx = exit.stream.then_some(response_rx);
 
     let upload_join = {
         let stop_download = stop_download.clone();
-        let target_url = target_url.clone();
+        let exit = exit.clone();
+        let (cause, closed) = (cause.clone(), closed.clone());
         let s_read = artex(s_read);
         async move {
             #[allow(clippy::never_loop)]
             loop {
-                let stream = WrapperBuilder {
-                    guard: s_read.clone().try_lock_owned().unwrap(),
-                    fr_builder: |a| FramedRead::new(a, BytesCodec::new()),
-                }
-                .build();
-
-                let stream = stream.map_while::<Result<_, anyhow::Error>, _>(|x| match x {
-                    Ok(x) => Some(Ok(x)),
-                    Err(x) => {
-                        dbg!(x);
-                        None
+                let failed = CancellationToken::new();
+                let stream = client_body(&s_read, failed.clone());
+                let upload = match response_tx.take() {
+                    Some(tx) => Either::Left(stream_req(&exit, uid, valve.wrap(stream), tx)),
+                    None => Either::Right(upload_body(&exit, uid, valve.wrap(stream))),
+                };
+                tokio::pin!(upload);
+                let finished = tokio::select! {
+                    x = &mut upload => x,
+                    () = failed.cancelled() => {
+                        // the exit resets the target before the body ends
+                        cause.set(CloseReason::ClientReset);
+                        let reason = close_session(&exit, uid, cause.get()).await;
+                        closed.set(reason.unwrap_or(CloseReason::ClientReset));
+                        false
                     }
-                });
-                let stream = valve.wrap(stream);
-                upload_req(&target_url, uid, stream).await;
-                //200
+                };
+                if finished {
+                    return true;
+                }
                 break;
             }
             stop_download.cancel();
+            false
         }
     };
 
     let download_join = {
-        let target_url = target_url.clone();
+        let exit = exit.clone();
+        let cause = cause.clone();
+        let stop_download = stop_download.clone();
         async move {
             #[allow(clippy::never_loop)]
             loop {
-                use futures::TryStreamExt;
-                use tokio_util::compat::FuturesAsyncReadCompatExt;
-
-                let s = download_req(&target_url, uid).await;
-                let mut r = s
-                    .map_while(|x| match x {
-                        Ok(x) => Some(Ok(x)),
-                        Err(x) => {
-                            dbg!(x);
-                            None
+                let finished = match response_rx.take() {
+                    Some(rx) => match rx.await {
+                        Ok(resp) => {
+                            copy_down(resp, exit.encoding, &mut s_write, &stop_download, &cause)
+                                .await
                         }
-                    })
-                    .into_async_read()
-                    .compat();
-
-                tokio::select! {
-                    x = tokio::io::copy(&mut r, &mut s_write) => x.expect("unreachable"),
-                    _ = stop_download.cancelled() => break,
+                        // the upload gave up before the exit answered
+                        Err(_) => break,
+                    },
+                    None => download_body(&exit, uid, &mut s_write, &stop_download, &cause).await,
                 };
-
+                // the target is done sending, the client may not be
+                if finished && s_write.shutdown().await.is_ok() {
+                    // dropping it would end the upload
+                    stop_upload.disable();
+                    return true;
+                }
                 break;
             }
             stop_upload.cancel();
+            false
         }
     };
+    let wait_join = tokio::spawn(wait_session(exit.clone(), uid, stop_download));
     let upload_join = tokio::spawn(upload_join);
     let download_join = tokio::spawn(download_join);
-    upload_join.await.unwrap();
-    download_join.await.unwrap();
-    close_session(&target_url, uid).await;
-    return Ok(uid);
+    let upload_finished = upload_join.await.unwrap();
+    if download_join.await.unwrap() && upload_finished {
+        cause.set(CloseReason::Fin);
+    }
+    wait_join.abort();
+    let reason = match closed.get() {
+        Some(x) => Some(x),
+        None => close_session(&exit, uid, cause.get()).await.or(cause.get()),
+    };
+    end_session(uid, reason, reset);
 }
 
-pub async fn main(
-    bind_addr: &[SocketAddr],
-    target_url: Url,
-) -> (SocketAddr, impl Future<Output = Infallible>) {
-    //console_subscriber::init();
+/// Closes the client connection as the exit saw the session end.
+fn end_session(uid: Uuid, reason: Option<CloseReason>, reset: Option<ResetHandle>) {
+    if let Some(reason) = reason {
+        println!("Closed session {uid:#x?}: {reason}");
+    }
+    if let (Some(CloseReason::TargetReset | CloseReason::Killed), Some(reset)) = (reason, reset) {
+        reset.reset();
+    }
+}
+
+/// Buffer between a [`TunnelStream`] and its transfer task.
+const TUNNEL_BUFFER: usize = 64 * 1024;
+
+/// A connection to the exit node's target, see [`connect`].
+#[derive(Debug)]
+pub struct TunnelStream {
+    uid: Uuid,
+    stream: DuplexStream,
+}
+
+impl TunnelStream {
+    #[must_use]
+    pub fn session(&self) -> Uuid {
+        self.uid
+    }
+}
+
+impl AsyncRead for TunnelStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TunnelStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Opens a session without a local listener, e.g. as custom connector of a database client.
+/// The data is carried by a spawned task, dropping the stream closes the session.
+///
+/// ```no_run
+/// # use tcp_over_http::entry::{self, EntryOptions, ExitNode};
+/// # use std::sync::Arc;
+/// # use tokio::io::AsyncWriteExt;
+/// # async fn run() {
+/// let url = "https://example.com/tunnel/".parse().unwrap();
+/// let exit = Arc::new(ExitNode::new(url, &EntryOptions::default()).unwrap());
+/// let mut stream = entry::connect(exit).await.unwrap();
+/// stream.write_all(b"hello").await.unwrap();
+/// # }
+/// ```
+///
+/// Takes a pooled session while [`main`] keeps a pool with `--pool`.
+///
+/// # Errors
+/// If the exit node refuses the session or cannot reach its target.
+pub async fn connect(exit: Arc<ExitNode>) -> anyhow::Result<TunnelStream> {
+    let uid = match exit.pool.as_ref().and_then(SessionPool::take) {
+        Some(uid) => uid,
+        None => init_http_session(&exit, None, None).await?.0,
+    };
+    let (stream, far) = tokio::io::duplex(TUNNEL_BUFFER);
+    tokio::spawn(transfer(exit, uid, far, None));
+    Ok(TunnelStream { uid, stream })
+}
+
+fn spawn_session<S>(
+    exit: Arc<ExitNode>,
+    socket: S,
+    peer: Option<(SocketAddr, SocketAddr)>,
+    reset: Option<ResetHandle>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
+{
+    let _join_handle = tokio::spawn(async move {
+        #[cfg(test)]
+        AC.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        drop(dbg!(process_socket(exit, socket, peer, reset).await));
+        #[cfg(test)]
+        AC.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+    });
+}
+
+/// Datagrams of one client waiting for its session.
+const UDP_QUEUE: usize = 64;
+
+/// Opens a session per client address, which lasts until the exit ends it.
+async fn serve_udp(exit: Arc<ExitNode>, socket: Arc<UdpSocket>) -> Infallible {
+    let local = socket.local_addr().unwrap();
+    let clients = Arc::new(std::sync::Mutex::new(Map::new()));
+    let mut buf = vec![0; udp::MAX_DATAGRAM];
+    loop {
+        let (len, client) = socket.recv_from(&mut buf).await.unwrap();
+        let datagram = Bytes::copy_from_slice(&buf[..len]);
+        let queue = clients.lock().unwrap().get(&client).cloned();
+        let queue = queue.unwrap_or_else(|| {
+            let (queue, received) = mpsc::channel(UDP_QUEUE);
+            clients.lock().unwrap().insert(client, queue.clone());
+            let stream = udp::bridge_client(socket.clone(), client, received);
+            let exit = exit.clone();
+            let clients = clients.clone();
+            tokio::spawn(async move {
+                drop(dbg!(
+                    process_socket(exit, stream, Some((client, local)), None).await
+                ));
+                clients.lock().unwrap().remove(&client);
+            });
+            queue
+        });
+        // dropped when full, as by a congested link
+        let _ = queue.try_send(datagram);
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Udp(Arc<UdpSocket>),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+async fn bind_tcp(bind_addr: &[SocketAddr]) -> TcpListener {
     let listener_result = TcpListener::bind(bind_addr).await;
     if let Err(bind_err) = listener_result {
         match bind_err.kind() {
@@ -177,46 +981,93 @@ pub async fn main(
         }
         panic!();
     };
-    let listener = listener_result.unwrap();
-    let bound = listener.local_addr().unwrap();
+    listener_result.unwrap()
+}
+
+pub async fn main(
+    bind_addr: &Endpoint,
+    target_url: Url,
+    options: EntryOptions,
+) -> (Endpoint, impl Future<Output = Infallible>) {
+    //console_subscriber::init();
+    let exit = match ExitNode::new(target_url, &options) {
+        Ok(x) => Arc::new(x),
+        Err(x) => {
+            eprintln!("Could not set up the HTTP client: {x:#}");
+            panic!();
+        }
+    };
+    if !options.skip_health_check {
+        if let Err(x) = check_exit(&exit).await {
+            eprintln!("{x}");
+            panic!();
+        }
+    }
+    if exit.pool.is_some() {
+        tokio::spawn(pool::fill(exit.clone()));
+    }
+    let (listener, bound) = match bind_addr {
+        Endpoint::Tcp(addrs) => {
+            let listener = bind_tcp(addrs).await;
+            let bound = Endpoint::Tcp(vec![listener.local_addr().unwrap()]);
+            (Listener::Tcp(listener), bound)
+        }
+        Endpoint::Udp(addrs) => match UdpSocket::bind(addrs.as_slice()).await {
+            Ok(x) => {
+                let bound = Endpoint::Udp(vec![x.local_addr().unwrap()]);
+                (Listener::Udp(Arc::new(x)), bound)
+            }
+            Err(x) => {
+                eprintln!("Could not listen on {bind_addr}: {x}");
+                panic!();
+            }
+        },
+        #[cfg(unix)]
+        Endpoint::Unix(path) => {
+            remove_stale_socket(path);
+            match UnixListener::bind(path) {
+                Ok(x) => (Listener::Unix(x), bind_addr.clone()),
+                Err(x) => {
+                    eprintln!("Could not listen on {}: {x}", path.display());
+                    panic!();
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        Endpoint::Unix(_) => {
+            eprintln!("Unix sockets are not supported on this platform.");
+            panic!();
+        }
+        Endpoint::Command(_) => {
+            eprintln!("Can not listen on {bind_addr}.");
+            panic!();
+        }
+    };
     println!("Listening on {bound}");
-    let target_url = Arc::new(target_url);
     return (bound, async move {
         loop {
-            let (socket, _) = listener.accept().await.unwrap();
-            let target_url = target_url.clone();
-            let _join_handle = tokio::spawn(async move {
-                #[cfg(test)]
-                AC.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-                drop(dbg!(process_socket(target_url, socket).await));
-                #[cfg(test)]
-                AC.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
-            });
+            match &listener {
+                Listener::Tcp(x) => {
+                    let (socket, _) = x.accept().await.unwrap();
+                    let peer = socket.peer_addr().ok().zip(socket.local_addr().ok());
+                    match ResetHandle::new(socket) {
+                        Ok((socket, reset)) => {
+                            spawn_session(exit.clone(), socket, peer, Some(reset));
+                        }
+                        Err(x) => drop(dbg!(x)),
+                    }
+                }
+                Listener::Udp(x) => match serve_udp(exit.clone(), x.clone()).await {},
+                // no addresses to pass on
+                #[cfg(unix)]
+                Listener::Unix(x) => {
+                    let (socket, _) = x.accept().await.unwrap();
+                    spawn_session(exit.clone(), socket, None, None);
+                }
+            }
         }
     });
 }
 
 #[cfg(test)]
 pub(crate) static AC: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
-
-#[track_caller]
-fn assert_ok(resp: Response) -> impl std::future::Future<Output = Response> {
-    #[cfg(feature = "rustc_stable")]
-    return async move { resp };
-    #[cfg(not(feature = "rustc_stable"))]
-    {
-        let blame_caller = std::intrinsics::caller_location();
-        async move {
-            if resp.status() == reqwest::StatusCode::OK {
-                return resp;
-            }
-            let s = format!("{resp:#?}");
-            let bytes = resp.bytes().await;
-            let resp_body = bytes.as_ref().map(|x| std::str::from_utf8(x));
-            eprintln!("{s}");
-            eprintln!("{resp_body:#?}");
-            eprintln!("caller: {blame_caller}");
-            panic!("status was not ok");
-        }
-    }
-}
@@ -27,6 +27,18 @@ impl TraceError {
     }
 }
 
+impl std::fmt::Display for TraceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(message) = &self.message {
+            write!(f, "{message}: ")?;
+        }
+        write!(f, "{} at {}", self.error, self.source)
+    }
+}
+
+/// For the public API, as an [`anyhow::Error`].
+impl std::error::Error for TraceError {}
+
 pub(crate) type Trace<T> = Result<T, TraceError>;
 
 pub(crate) trait TraceExt {
@@ -1,21 +1,277 @@
-use crate::artex;
-use crate::{ouroboros_impl_wrapper::WrapperBuilder, Artex};
-use actix_web::dev::Server;
-use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder};
-use anyhow::anyhow;
-use futures::stream::TryStreamExt;
+use crate::close::{CloseCause, ResetHandle};
+use crate::decoy::{self, Decoy};
+use crate::encoding::{self, BodyEncoding};
+use crate::entry::http2_window;
+use crate::limit::{Admission, KeyedLimiter, Throttle, Ticket, TokenBucket};
+use crate::proxy_protocol;
+#[cfg(unix)]
+use crate::remove_stale_socket;
+use crate::tls;
+use crate::udp;
+use crate::{admin, ouroboros_impl_wrapper::WrapperBuilder, Artex};
+use crate::{artex, same_secret, Endpoint};
+use actix_http::body::MessageBody;
+use actix_http::{Extensions, HttpService};
+use actix_service::map_config;
+use actix_tls::accept::rustls_0_20::TlsStream;
+use actix_web::dev::{
+    fn_factory, fn_service, AppConfig, Server, Service, ServiceFactory, ServiceRequest,
+    ServiceResponse,
+};
+use actix_web::guard;
+use actix_web::http::{header, header::HeaderValue, Version};
+use actix_web::{get, post, route, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use futures::stream::{StreamExt, TryStreamExt};
 use halfbrown::HashMap as Map;
+use rustls::ServerConfig;
+use std::collections::VecDeque;
+use std::io::ErrorKind;
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use stream_cancel::{Trigger, Valve};
-use tokio::{net::TcpStream, sync::RwLock};
+use tokio::io::ReadBuf;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::{Notify, RwLock};
 use tokio_util::codec::{BytesCodec, FramedRead};
 use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-#[derive(Debug)]
+pub use crate::close::CloseReason;
+pub use crate::decoy::DecoyOptions;
+pub use crate::limit::ByteSize;
+pub use crate::proxy_protocol::ProxyProtocol;
+pub use crate::tls::TlsServerOptions;
+
+#[derive(Clone, Debug, Default, clap::Args)]
+pub struct ExitOptions {
+    /// Serve session inspection and control endpoints under `/admin/`,
+    /// for requests with `Authorization: Bearer <--admin-token>`.
+    #[clap(long, requires = "admin_token")]
+    pub admin: bool,
+
+    /// Token the admin endpoints require. Requests without it get the decoy.
+    #[clap(long, value_name = "TOKEN")]
+    pub admin_token: Option<String>,
+
+    /// Bandwidth limit of a single session per direction, in bytes per second (suffixes K, M, G).
+    #[clap(long, value_name = "BYTES", value_parser = ByteSize::nonzero)]
+    pub session_rate: Option<ByteSize>,
+
+    /// Bandwidth limit of all sessions together per direction, in bytes per second.
+    #[clap(long, value_name = "BYTES", value_parser = ByteSize::nonzero)]
+    pub global_rate: Option<ByteSize>,
+
+    /// Number of sessions a single client may open per second, told apart like
+    /// --max-sessions-per-client does.
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub open_rate: Option<u64>,
+
+    /// Maximum number of concurrent sessions.
+    #[clap(long, value_name = "N")]
+    pub max_sessions: Option<usize>,
+
+    /// Maximum number of concurrent sessions of a single client.
+    #[clap(long, value_name = "N")]
+    pub max_sessions_per_client: Option<usize>,
+
+    /// Identify clients by this request header (e.g. an API key or X-Forwarded-For)
+    /// instead of their IP address.
+    #[clap(long, value_name = "NAME")]
+    pub client_header: Option<header::HeaderName>,
+
+    /// When at a session limit, wait this many seconds for a free slot
+    /// before answering 503, instead of answering right away.
+    #[clap(long, value_name = "SECS")]
+    pub queue_timeout: Option<u64>,
+
+    /// Send a PROXY protocol header to the target, carrying the address of the client as the
+    /// exit sees it, taken from --client-header if that holds an IP address.
+    #[clap(long, value_enum, value_name = "VERSION")]
+    pub proxy_protocol: Option<ProxyProtocol>,
+
+    /// Carry the address of the client connected to the entry node in the PROXY header
+    /// instead. Anyone able to open sessions could fake it, so only for trusted entries,
+    /// e.g. behind --tunnel-token.
+    #[clap(long, requires = "proxy_protocol")]
+    pub trust_entry_address: bool,
+
+    #[clap(flatten)]
+    pub tls: TlsServerOptions,
+
+    /// Also accept HTTP/2 with prior knowledge (h2c) on plaintext TCP, for entries started
+    /// with --http2. Over TLS, HTTP/2 is offered through ALPN anyway.
+    #[clap(long)]
+    pub h2c: bool,
+
+    /// Initial HTTP/2 window of each stream, the bytes an entry may send ahead per upload.
+    /// Defaults to actix's 1MiB.
+    #[clap(long, value_name = "BYTES")]
+    pub http2_stream_window: Option<ByteSize>,
+
+    /// Initial HTTP/2 window of the connection, shared by all uploads.
+    /// Defaults to actix's 2MiB.
+    #[clap(long, value_name = "BYTES")]
+    pub http2_connection_window: Option<ByteSize>,
+
+    /// Serve all endpoints below this path, e.g. `/tunnel/secret123/`.
+    /// The entry's --target-url has to include it.
+    #[clap(long, value_name = "PATH", default_value = "/")]
+    pub path_prefix: String,
+
+    /// Only requests with this in an `X-Tunnel-Token` header reach the tunnel endpoints.
+    /// All others get the decoy, so scanners can not open sessions. The health checks stay
+    /// open for load balancers, which can not send it.
+    /// Entries send it with `-H 'X-Tunnel-Token: <TOKEN>'`.
+    #[clap(long, value_name = "TOKEN")]
+    pub tunnel_token: Option<String>,
+
+    #[clap(flatten)]
+    pub decoy: DecoyOptions,
+
+    /// Seconds without a datagram in either direction after which a session to a `udp:` target ends.
+    /// 0 keeps it open until the entry closes it.
+    #[clap(long, value_name = "SECS", default_value_t = 60)]
+    pub udp_idle_timeout: u64,
+}
+
+pub(crate) type TargetRead = Box<dyn AsyncRead + Send + Sync + Unpin>;
+pub(crate) type TargetWrite = Box<dyn AsyncWrite + Send + Sync + Unpin>;
+
+/// Stdout of a target command, which is killed when this is dropped.
+struct CommandOutput {
+    stdout: ChildStdout,
+    _child: Child,
+}
+
+impl AsyncRead for CommandOutput {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+/// Stdin of a target command, closed on shutdown.
+struct CommandInput(Option<ChildStdin>);
+
+impl AsyncWrite for CommandInput {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match &mut self.0 {
+            Some(x) => Pin::new(x).poll_write(cx, buf),
+            None => Poll::Ready(Err(ErrorKind::BrokenPipe.into())),
+        }
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut self.0 {
+            Some(x) => Pin::new(x).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.0 = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A connected target, split into both directions.
+pub(crate) struct TargetConn {
+    read: TargetRead,
+    write: TargetWrite,
+    reset: Option<ResetHandle>,
+}
+
+impl TargetConn {
+    fn new(
+        read: impl AsyncRead + Send + Sync + Unpin + 'static,
+        write: impl AsyncWrite + Send + Sync + Unpin + 'static,
+    ) -> Self {
+        Self {
+            read: Box::new(read),
+            write: Box::new(write),
+            reset: None,
+        }
+    }
+}
+
+/// Connects to the target. `cause` learns about an expired `udp:` target.
+async fn connect_target(
+    target: &Endpoint,
+    udp_idle: Option<Duration>,
+    cause: &CloseCause,
+) -> std::io::Result<TargetConn> {
+    match target {
+        Endpoint::Tcp(x) => {
+            let (stream, reset) = ResetHandle::new(TcpStream::connect(x.as_slice()).await?)?;
+            // unlike into_split, no FIN when the write half drops before a reset
+            let (read, write) = tokio::io::split(stream);
+            Ok(TargetConn {
+                reset: Some(reset),
+                ..TargetConn::new(read, write)
+            })
+        }
+        Endpoint::Udp(x) => {
+            let stream = udp::connect(x, udp_idle, cause.clone()).await?;
+            let (read, write) = tokio::io::split(stream);
+            Ok(TargetConn::new(read, write))
+        }
+        #[cfg(unix)]
+        Endpoint::Unix(x) => {
+            let (read, write) = UnixStream::connect(x).await?.into_split();
+            Ok(TargetConn::new(read, write))
+        }
+        #[cfg(not(unix))]
+        Endpoint::Unix(_) => Err(std::io::Error::new(
+            ErrorKind::Unsupported,
+            "Unix sockets are not supported on this platform",
+        )),
+        Endpoint::Command(x) => {
+            let mut child = Command::new("sh")
+                .arg("-c")
+                .arg(x)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()?;
+            let stdin = child.stdin.take().unwrap();
+            let stdout = child.stdout.take().unwrap();
+            let read = CommandOutput {
+                stdout,
+                _child: child,
+            };
+            Ok(TargetConn::new(read, CommandInput(Some(stdin))))
+        }
+    }
+}
+
+#[derive(Derivative)]
+#[derivative(Debug)]
 pub(crate) struct UpExitSession {
-    pub(crate) tcp_out: Artex<tokio::net::tcp::OwnedWriteHalf>,
+    #[derivative(Debug = "ignore")]
+    pub(crate) tcp_out: Artex<TargetWrite>,
     pub(crate) stop_copy: CancellationToken,
+    pub(crate) bytes: Arc<AtomicU64>,
+    limit: Option<Arc<TokenBucket>>,
+    /// Of the next upload part, parts wait for their turn. [`PART_CLAIMED`] is set while it
+    /// is being copied.
+    next_part: Arc<AtomicU64>,
+    #[derivative(Debug = "ignore")]
+    part_done: Arc<Notify>,
 }
 
 use derivative::Derivative;
@@ -23,170 +279,1200 @@ use derivative::Derivative;
 #[derive(Derivative)]
 #[derivative(Debug)]
 pub(crate) struct DownExitSession {
-    pub(crate) tcp_in: Artex<tokio::net::tcp::OwnedReadHalf>,
+    #[derivative(Debug = "ignore")]
+    pub(crate) tcp_in: Artex<TargetRead>,
     #[derivative(Debug = "ignore")]
     stream_valve: Valve,
     #[derivative(Debug = "ignore")]
     stop_stream: Trigger,
+    pub(crate) bytes: Arc<AtomicU64>,
+    #[derivative(Debug = "ignore")]
+    limit: Option<Arc<TokenBucket>>,
+    /// The target ended within a download part, the next one answers 410 Gone.
+    eof: Arc<AtomicBool>,
 }
 
+/// Bits of [`ExitSession::open_directions`].
+const UP: u8 = 0b01;
+const DOWN: u8 = 0b10;
+
 #[derive(Debug)]
 pub(crate) struct ExitSession {
     pub(crate) up: UpExitSession,
     pub(crate) down: DownExitSession,
+    pub(crate) client: Option<SocketAddr>,
+    pub(crate) opened: Instant,
+    encoding: BodyEncoding,
+    cause: CloseCause,
+    reset: Option<ResetHandle>,
+    /// [`UP`] and [`DOWN`] while still transferring, the exit closes the session once none is.
+    open_directions: Arc<AtomicU8>,
+    /// Cancelled once the session is closed, for `/wait`.
+    ended: CancellationToken,
+    _ticket: Ticket,
 }
 impl ExitSession {
-    fn new(conn: TcpStream) -> Self {
-        let (down, up) = conn.into_split();
+    fn new(
+        conn: TargetConn,
+        cause: CloseCause,
+        client: Option<SocketAddr>,
+        rate: Option<ByteSize>,
+        encoding: BodyEncoding,
+        ticket: Ticket,
+    ) -> Self {
+        let TargetConn {
+            read: down,
+            write: up,
+            reset,
+        } = conn;
         let (trigger, valve) = Valve::new();
+        let bucket = || rate.map(|x| Arc::new(TokenBucket::per_second(x.0)));
         ExitSession {
             up: UpExitSession {
                 tcp_out: artex(up),
                 stop_copy: CancellationToken::new(),
+                bytes: Arc::default(),
+                limit: bucket(),
+                next_part: Arc::default(),
+                part_done: Arc::default(),
             },
             down: DownExitSession {
                 tcp_in: artex(down),
                 stream_valve: valve,
                 stop_stream: trigger,
+                bytes: Arc::default(),
+                limit: bucket(),
+                eof: Arc::default(),
             },
+            client,
+            opened: Instant::now(),
+            encoding,
+            cause,
+            reset,
+            open_directions: Arc::new(AtomicU8::new(UP | DOWN)),
+            ended: CancellationToken::new(),
+            _ticket: ticket,
+        }
+    }
+    fn close(self, reason: CloseReason) {
+        // mirror an abort of the client
+        if let (CloseReason::ClientReset | CloseReason::Killed, Some(reset)) = (reason, &self.reset)
+        {
+            reset.reset();
         }
+        self.down.stop_stream.cancel();
+        self.up.stop_copy.cancel();
+        self.ended.cancel();
     }
 }
 
+/// Counters over the whole lifetime of the exit node.
+#[derive(Debug, Default)]
+pub(crate) struct ExitStats {
+    pub(crate) opened: AtomicU64,
+    pub(crate) closed: AtomicU64,
+    pub(crate) bytes_up: AtomicU64,
+    pub(crate) bytes_down: AtomicU64,
+    /// Indexed by [`CloseReason`].
+    pub(crate) closed_by: [AtomicU64; CloseReason::ALL.len()],
+}
+
+/// Sessions which ended without the entry knowing yet.
+const MAX_ENDED: usize = 1024;
+
 #[derive(Debug)]
 pub(crate) struct ExitSessionManager {
-    target_addr: Vec<SocketAddr>,
+    target_addr: Endpoint,
+    options: ExitOptions,
     pub(crate) sessions: RwLock<Map<Uuid, ExitSession>>,
+    pub(crate) stats: ExitStats,
+    /// Set through the admin API. New sessions are refused, open ones keep running.
+    pub(crate) draining: AtomicBool,
+    global_up: Option<Arc<TokenBucket>>,
+    global_down: Option<Arc<TokenBucket>>,
+    open_limit: Option<KeyedLimiter<String>>,
+    pub(crate) admission: Admission,
+    /// Reasons of sessions ended on this side, until the entry asks for them.
+    ended: std::sync::Mutex<VecDeque<(Uuid, CloseReason)>>,
 }
 
 impl ExitSessionManager {
-    fn new(target_addr: Vec<SocketAddr>) -> Self {
+    fn new(target_addr: Endpoint, options: ExitOptions) -> Self {
+        let global = || {
+            options
+                .global_rate
+                .map(|x| Arc::new(TokenBucket::per_second(x.0)))
+        };
         Self {
             target_addr,
             sessions: tokio::sync::RwLock::new(Map::new()),
+            stats: ExitStats::default(),
+            draining: AtomicBool::new(false),
+            global_up: global(),
+            global_down: global(),
+            open_limit: options.open_rate.map(KeyedLimiter::per_second),
+            admission: Admission::new(
+                options.max_sessions,
+                options.max_sessions_per_client,
+                options.queue_timeout.map(Duration::from_secs),
+            ),
+            ended: std::sync::Mutex::default(),
+            options,
+        }
+    }
+
+    fn udp_idle(&self) -> Option<Duration> {
+        match self.options.udp_idle_timeout {
+            0 => None,
+            x => Some(Duration::from_secs(x)),
+        }
+    }
+
+    /// Removes the session and stops its transfers. `reason` counts
+    /// unless one was noticed earlier, the one used is returned.
+    /// `None` if there was no such session.
+    pub(crate) async fn close(&self, uid: Uuid, reason: CloseReason) -> Option<CloseReason> {
+        let sess = self.sessions.write().await.remove(&uid)?;
+        sess.cause.set(reason);
+        let reason = sess.cause.get().unwrap();
+        println!("Close session {uid:#x?}: {reason}");
+        self.stats.closed.fetch_add(1, Ordering::Relaxed);
+        self.stats.closed_by[reason as usize].fetch_add(1, Ordering::Relaxed);
+        sess.close(reason);
+        Some(reason)
+    }
+
+    /// Closes the session without the entry asking,
+    /// which learns the reason when it does.
+    pub(crate) async fn end(&self, uid: Uuid, reason: CloseReason) -> bool {
+        let Some(reason) = self.close(uid, reason).await else {
+            return false;
+        };
+        let mut ended = self.ended.lock().unwrap();
+        if ended.len() == MAX_ENDED {
+            ended.pop_front();
+        }
+        ended.push_back((uid, reason));
+        true
+    }
+
+    /// `direction` of the session is done, the last one ends it. Again for the same is a no-op.
+    async fn half_close(&self, uid: Uuid, open_directions: &AtomicU8, direction: u8) {
+        if open_directions.fetch_and(!direction, Ordering::Relaxed) == direction {
+            self.end(uid, CloseReason::Fin).await;
         }
     }
+
+    /// Picks what a request needs of the session with `f`, `None` for an unknown one.
+    async fn find<T>(&self, uid_s: &str, f: impl FnOnce(&ExitSession) -> T) -> Option<(Uuid, T)> {
+        let uid = Uuid::parse_str(uid_s).ok()?;
+        let sessions = self.sessions.read().await;
+        Some((uid, f(sessions.get(&uid)?)))
+    }
+
+    fn take_ended(&self, uid: Uuid) -> Option<CloseReason> {
+        let mut ended = self.ended.lock().unwrap();
+        let i = ended.iter().position(|x| x.0 == uid)?;
+        ended.remove(i).map(|x| x.1)
+    }
+}
+
+/// The client as the exit sees it, by --client-header if that holds an IP address,
+/// e.g. the first of an `X-Forwarded-For` list.
+fn client_addr(req: &HttpRequest, options: &ExitOptions) -> Option<SocketAddr> {
+    let forwarded = client_header(req, options);
+    let ip = forwarded.and_then(|x| x.to_str().ok()?.split(',').next()?.trim().parse().ok());
+    match ip {
+        Some(ip) => Some(SocketAddr::new(ip, 0)),
+        None => req.peer_addr(),
+    }
+}
+
+/// The --client-header of `req`, if set.
+fn client_header<'a>(req: &'a HttpRequest, options: &ExitOptions) -> Option<&'a HeaderValue> {
+    req.headers().get(options.client_header.as_ref()?)
+}
+
+/// What the limits per client count by, --client-header or else the IP address.
+fn client_key(req: &HttpRequest, options: &ExitOptions) -> String {
+    client_header(req, options)
+        .map(|x| String::from_utf8_lossy(x.as_bytes()).into_owned())
+        .or_else(|| req.peer_addr().map(|x| x.ip().to_string()))
+        .unwrap_or_default()
+}
+
+/// The address a connection was accepted on, where the [`AppConfig`] does not know it.
+#[derive(Clone, Copy, Debug)]
+struct LocalAddr(SocketAddr);
+
+/// Addresses of the TCP connection accepted by the entry node.
+#[derive(Debug, serde::Deserialize)]
+struct OpenQuery {
+    src: Option<SocketAddr>,
+    dst: Option<SocketAddr>,
+    /// Milliseconds to wait for the target's first bytes,
+    /// if the body holds what the client sent already.
+    wait: Option<u64>,
+    /// Of the bodies of this request and the session.
+    #[serde(default)]
+    encoding: BodyEncoding,
+}
+
+/// Carries [`ExitOptions::tunnel_token`].
+pub(crate) const TUNNEL_TOKEN_HEADER: &str = "x-tunnel-token";
+
+pub(crate) fn has_tunnel_token(headers: &header::HeaderMap, token: &str) -> bool {
+    let given = headers.get(TUNNEL_TOKEN_HEADER);
+    given.is_some_and(|x| same_secret(x.as_bytes(), token))
 }
 
-#[get("/open")]
-async fn open(manager: web::Data<ExitSessionManager>) -> Vec<u8> {
-    let stream = match TcpStream::connect(manager.target_addr.as_slice()).await {
+/// Upper bound of [`OpenQuery::wait`].
+const MAX_EARLY_WAIT: Duration = Duration::from_secs(1);
+
+/// Most of the target's first bytes in the open response.
+const EARLY_DATA_MAX: usize = 16 * 1024;
+
+#[route("/open", method = "GET", method = "POST")]
+async fn open(
+    manager: web::Data<ExitSessionManager>,
+    decoy: web::Data<Decoy>,
+    req: HttpRequest,
+    query: web::Query<OpenQuery>,
+    body: web::Bytes,
+) -> HttpResponse {
+    if manager.draining.load(Ordering::Relaxed) {
+        //signal
+        return HttpResponse::Ok().finish();
+    }
+    let Ok(body) = query.encoding.decode(body.clone()) else {
+        return decoy::respond_read(decoy, req, body).await;
+    };
+    let client = client_key(&req, &manager.options);
+    if let Some(limit) = &manager.open_limit {
+        if let Err(wait) = limit.check(client.clone()) {
+            return HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, wait.as_secs() + 1))
+                .finish();
+        }
+    }
+    let Some(ticket) = manager.admission.admit(&client).await else {
+        return HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, 1))
+            .body("session limit reached");
+    };
+    let cause = CloseCause::default();
+    let mut conn = match connect_target(&manager.target_addr, manager.udp_idle(), &cause).await {
         Ok(x) => x,
         Err(x) => {
             dbg!(x, "couldnt connect to target");
             //signal
-            return vec![];
+            return HttpResponse::Ok().finish();
         }
     };
+    // would be taken for a datagram
+    let udp = matches!(manager.target_addr, Endpoint::Udp(_));
+    if let (Some(version), false) = (manager.options.proxy_protocol, udp) {
+        let addrs = if manager.options.trust_entry_address {
+            query.src.zip(query.dst)
+        } else {
+            let local = req.conn_data::<LocalAddr>().map(|x| x.0);
+            let local = local.unwrap_or_else(|| req.app_config().local_addr());
+            client_addr(&req, &manager.options).zip(Some(local))
+        };
+        let header = proxy_protocol::header(version, addrs);
+        if let Err(x) = conn.write.write_all(&header).await {
+            dbg!(x, "couldnt send PROXY header");
+            //signal
+            return HttpResponse::Ok().finish();
+        }
+    }
+    let mut early = Vec::new();
+    if let Some(wait) = query.wait {
+        if let Err(x) = conn.write.write_all(&body).await {
+            dbg!(x, "couldnt send early data");
+            //signal
+            return HttpResponse::Ok().finish();
+        }
+        let wait = Duration::from_millis(wait).min(MAX_EARLY_WAIT);
+        early = vec![0; EARLY_DATA_MAX];
+        match tokio::time::timeout(wait, conn.read.read(&mut early)).await {
+            Ok(Ok(len)) => early.truncate(len),
+            Ok(Err(x)) => {
+                dbg!(x, "target disconnect");
+                //signal
+                return HttpResponse::Ok().finish();
+            }
+            // nothing yet, the download gets it
+            Err(_) => early.clear(),
+        }
+    }
     let uid = Uuid::new_v4();
-    let mut guard = manager.sessions.write().await;
-    guard.insert(uid, ExitSession::new(stream));
-    return uid.into_bytes().to_vec();
+    let sess = ExitSession::new(
+        conn,
+        cause,
+        req.peer_addr(),
+        manager.options.session_rate,
+        query.encoding,
+        ticket,
+    );
+    // passed already, what follows waits for them
+    Throttle::new([&sess.up.limit, &manager.global_up]).charge(body.len());
+    Throttle::new([&sess.down.limit, &manager.global_down]).charge(early.len());
+    let (up, down) = (body.len() as u64, early.len() as u64);
+    sess.up.bytes.fetch_add(up, Ordering::Relaxed);
+    sess.down.bytes.fetch_add(down, Ordering::Relaxed);
+    manager.stats.bytes_up.fetch_add(up, Ordering::Relaxed);
+    manager.stats.bytes_down.fetch_add(down, Ordering::Relaxed);
+    manager.sessions.write().await.insert(uid, sess);
+    manager.stats.opened.fetch_add(1, Ordering::Relaxed);
+    let body = [&uid.into_bytes()[..], &early].concat();
+    return HttpResponse::Ok()
+        .content_type(query.encoding.content_type())
+        .body(query.encoding.encode(body.into()));
 }
 
-#[post("/upload/{uid_s}")]
-async fn upload(
+/// What a transfer towards the target needs of its session.
+struct UpPart {
+    tcp_out: Artex<TargetWrite>,
+    stop_copy: CancellationToken,
+    bytes: Arc<AtomicU64>,
+    throttle: Throttle,
+    next_part: Arc<AtomicU64>,
+    part_done: Arc<Notify>,
+    encoding: BodyEncoding,
+    cause: CloseCause,
+    open_directions: Arc<AtomicU8>,
+}
+
+impl UpPart {
+    fn new(manager: &ExitSessionManager, sess: &ExitSession) -> Self {
+        Self {
+            tcp_out: sess.up.tcp_out.clone(),
+            stop_copy: sess.up.stop_copy.clone(),
+            bytes: sess.up.bytes.clone(),
+            throttle: Throttle::new([&sess.up.limit, &manager.global_up]),
+            next_part: sess.up.next_part.clone(),
+            part_done: sess.up.part_done.clone(),
+            encoding: sess.encoding,
+            cause: sess.cause.clone(),
+            open_directions: sess.open_directions.clone(),
+        }
+    }
+}
+
+/// What a transfer from the target needs of its session.
+struct DownPart {
+    tcp_in: Artex<TargetRead>,
+    valve: Valve,
+    bytes: Arc<AtomicU64>,
+    throttle: Throttle,
+    eof: Arc<AtomicBool>,
+    encoding: BodyEncoding,
+    cause: CloseCause,
+    open_directions: Arc<AtomicU8>,
+}
+
+impl DownPart {
+    fn new(manager: &ExitSessionManager, sess: &ExitSession) -> Self {
+        Self {
+            tcp_in: sess.down.tcp_in.clone(),
+            valve: sess.down.stream_valve.clone(),
+            bytes: sess.down.bytes.clone(),
+            throttle: Throttle::new([&sess.down.limit, &manager.global_down]),
+            eof: sess.down.eof.clone(),
+            encoding: sess.encoding,
+            cause: sess.cause.clone(),
+            open_directions: sess.open_directions.clone(),
+        }
+    }
+}
+
+/// Copies the entry's body to the target and tells how that ended.
+/// With `fin` the target gets a FIN after it, else more parts follow.
+async fn copy_up(
     manager: web::Data<ExitSessionManager>,
-    uid_s: web::Path<String>,
+    uid: Uuid,
+    part: UpPart,
     http_receive_data: web::Payload,
-) -> impl Responder {
-    let (guard, stop_copy) = {
-        let uid = Uuid::parse_str(&uid_s).unwrap();
-        let guard = manager.sessions.read().await;
-        let up_sess = &guard
-            .get(&uid)
-            .ok_or_else(|| anyhow!("Session {:#x?} not found!", uid))
-            .unwrap()
-            .up;
-        (
-            up_sess.tcp_out.clone().lock_owned(),
-            up_sess.stop_copy.clone(),
-        )
-    };
-    let r = http_receive_data
-        .map_err(|x| Err(x).unwrap())
-        .into_async_read();
+    fin: bool,
+) -> &'static str {
+    let UpPart {
+        tcp_out,
+        stop_copy,
+        bytes,
+        throttle,
+        encoding,
+        cause,
+        open_directions,
+        ..
+    } = part;
+    let mut payload = http_receive_data;
+    let guard = tcp_out.lock_owned();
+    let client_cause = cause.clone();
+    let r = encoding::decode_stream(encoding, &mut payload)
+        .and_then(move |x| {
+            let throttle = throttle.clone();
+            async move {
+                throttle.take(x.len()).await;
+                Ok(x)
+            }
+        })
+        .inspect_ok(|x| {
+            let len = x.len() as u64;
+            bytes.fetch_add(len, Ordering::Relaxed);
+            manager.stats.bytes_up.fetch_add(len, Ordering::Relaxed);
+        })
+        .map_err(|x| {
+            // the entry aborted the body, or garbled it
+            client_cause.set(CloseReason::ClientReset);
+            x
+        });
+    // throttling makes the stream !Unpin
+    let r = Box::pin(r).into_async_read();
     let mut r = tokio_util::compat::FuturesAsyncReadCompatExt::compat(r);
     let tcp_out = &mut *guard.await;
-    return tokio::select! {
+    let ended = tokio::select! {
         x = tokio::io::copy(&mut r, tcp_out) => {
+            // the entry's client is done sending, pass its FIN on, but not after a failed copy
+            let x = match x {
+                Ok(_) if fin => tcp_out.shutdown().await,
+                x => x.map(drop),
+            };
             if let Err(x) = x {
-                dbg!("target disconnect", x);
-                HttpResponse::Ok().body("target disconnect")
+                // an aborted or garbled body fails the copy as well
+                let (reason, answer) = if cause.get() == Some(CloseReason::ClientReset) {
+                    (CloseReason::ClientReset, "client disconnect")
+                } else {
+                    (CloseReason::TargetReset, "target disconnect")
+                };
+                dbg!(answer, x);
+                cause.set(reason);
+                manager.end(uid, reason).await;
+                answer
+            } else if fin {
+                manager.half_close(uid, &open_directions, UP).await;
+                "finished"
             } else {
-                HttpResponse::Ok().body("finished")
+                "received"
             }
         }
-        _ = stop_copy.cancelled() => {
-            HttpResponse::Ok().body("cancelled")
+        _ = stop_copy.cancelled() => "cancelled",
+    };
+    if !matches!(ended, "received" | "finished") {
+        drop(r);
+        drain(payload).await;
+    }
+    ended
+}
+
+/// How long an answer before the end of the request body waits for the rest.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reads what is left of a body not to be used, so the connection can take the next request
+/// once it is answered. The entry stops sending once it learns the session ended.
+pub(crate) async fn drain(payload: web::Payload) {
+    let rest = payload.for_each(|_| async {});
+    let _ = tokio::time::timeout(DRAIN_TIMEOUT, rest).await;
+}
+
+/// Limits of a download part, the entry asks for the rest in another request.
+#[derive(Debug, Default, serde::Deserialize)]
+struct DownloadQuery {
+    /// Bytes after which the part ends, with the read that reached them.
+    size: Option<u64>,
+    /// Seconds after which the part ends.
+    secs: Option<u64>,
+}
+
+/// What the target sends, until it ends. Aborts instead of ending if the session
+/// ended otherwise, not to be taken for a FIN by the entry.
+async fn stream_down(
+    manager: web::Data<ExitSessionManager>,
+    uid: Uuid,
+    part: DownPart,
+    limits: DownloadQuery,
+) -> impl futures::Stream<Item = std::io::Result<web::Bytes>> {
+    let DownPart {
+        tcp_in,
+        valve,
+        bytes,
+        throttle,
+        eof,
+        encoding,
+        cause,
+        open_directions,
+    } = part;
+    let in_parts = limits.size.is_some() || limits.secs.is_some();
+    // the part ended before the target did
+    let cut = CancellationToken::new();
+    let ended = {
+        let (manager, cause, cut) = (manager.clone(), cause.clone(), cut.clone());
+        async move {
+            match cause.get() {
+                None if cut.is_cancelled() => None,
+                // told with the next part
+                None if in_parts => {
+                    eof.store(true, Ordering::Relaxed);
+                    None
+                }
+                None => {
+                    manager.half_close(uid, &open_directions, DOWN).await;
+                    None
+                }
+                Some(CloseReason::Fin) => None,
+                Some(reason) => {
+                    manager.end(uid, reason).await;
+                    Some(Err(std::io::Error::other(reason.as_str())))
+                }
+            }
         }
     };
+    let stream = WrapperBuilder {
+        guard: tcp_in.lock_owned().await,
+        fr_builder: |a| FramedRead::new(a, BytesCodec::new()),
+    }
+    .build();
+    let until = {
+        let deadline = limits
+            .secs
+            .map(|x| tokio::time::Instant::now() + Duration::from_secs(x));
+        let cut = cut.clone();
+        async move {
+            match deadline {
+                Some(x) => tokio::select! {
+                    () = cut.cancelled() => {}
+                    () = tokio::time::sleep_until(x) => cut.cancel(),
+                },
+                None => cut.cancelled().await,
+            }
+        }
+    };
+    let mut left = limits.size;
+    let stream = valve
+        .wrap(stream)
+        .inspect(move |x| {
+            if let (Ok(x), Some(left)) = (x, &mut left) {
+                *left = left.saturating_sub(x.len() as u64);
+                if *left == 0 {
+                    cut.cancel();
+                }
+            }
+        })
+        .take_until(until);
+    // ends on a read error as well, so `ended` runs
+    let stream = tokio_stream::StreamExt::map_while(stream, move |x| match x {
+        Ok(x) => Some(Ok(x)),
+        Err(x) => {
+            dbg!(x, "target disconnect");
+            cause.set(CloseReason::TargetReset);
+            None
+        }
+    });
+    let stream = stream
+        .chain(futures::stream::once(ended).filter_map(futures::future::ready))
+        .and_then(move |x| {
+            let throttle = throttle.clone();
+            async move {
+                throttle.take(x.len()).await;
+                Ok(x)
+            }
+        })
+        .inspect_ok(move |x| {
+            let len = x.len() as u64;
+            bytes.fetch_add(len, Ordering::Relaxed);
+            manager.stats.bytes_down.fetch_add(len, Ordering::Relaxed);
+        });
+    stream.map_ok(move |x| encoding.encode(x.freeze()))
+}
+
+/// Marks [`UpExitSession::next_part`] as being copied.
+const PART_CLAIMED: u64 = 1 << 63;
+
+/// Numbers the upload parts of a session, for bodies of a bounded size.
+#[derive(Debug, serde::Deserialize)]
+struct UploadQuery {
+    /// Counts from 0, parts are copied in this order.
+    seq: Option<u64>,
+    /// The last part, the target gets a FIN after it.
+    #[serde(default)]
+    fin: bool,
+}
+
+/// The whole upload, or a part of it if the query has a `seq`.
+#[post("/upload/{uid_s}")]
+async fn upload(
+    manager: web::Data<ExitSessionManager>,
+    decoy: web::Data<Decoy>,
+    req: HttpRequest,
+    uid_s: web::Path<String>,
+    query: web::Query<UploadQuery>,
+    payload: web::Payload,
+) -> HttpResponse {
+    let Some((uid, part)) = manager.find(&uid_s, |x| UpPart::new(&manager, x)).await else {
+        return decoy::respond(decoy, req, payload).await;
+    };
+    let Some(seq) = query.seq else {
+        return HttpResponse::Ok().body(copy_up(manager, uid, part, payload, true).await);
+    };
+    let (next, done) = (part.next_part.clone(), part.part_done.clone());
+    loop {
+        // registered before the check, not to miss the previous part
+        let turn = done.notified();
+        match (next.load(Ordering::Relaxed) & !PART_CLAIMED).cmp(&seq) {
+            std::cmp::Ordering::Greater => {
+                drain(payload).await;
+                return HttpResponse::Conflict().body("part already received");
+            }
+            std::cmp::Ordering::Equal
+                if next
+                    .compare_exchange(
+                        seq,
+                        seq | PART_CLAIMED,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    )
+                    .is_ok() =>
+            {
+                break
+            }
+            // an earlier part, or a retry of this one, is still on its way
+            _ => tokio::select! {
+                () = turn => {}
+                () = part.stop_copy.cancelled() => {
+                    drain(payload).await;
+                    return HttpResponse::Ok().body("cancelled");
+                }
+            },
+        }
+    }
+    let result = copy_up(manager, uid, part, payload, query.fin).await;
+    let received = matches!(result, "received" | "finished");
+    next.store(if received { seq + 1 } else { seq }, Ordering::Relaxed);
+    done.notify_waiters();
+    HttpResponse::Ok().body(result)
 }
 
+/// In parts if the query limits them, until one answers 410 Gone as the target ended.
 #[get("/download/{uid_s}")]
 async fn download(
     manager: web::Data<ExitSessionManager>,
+    decoy: web::Data<Decoy>,
+    req: HttpRequest,
     uid_s: web::Path<String>,
-) -> impl Responder {
-    let (guard, valve) = {
-        let uid = Uuid::parse_str(&uid_s).unwrap();
-        let guard = manager.sessions.read().await;
-        let down_sess = &guard
-            .get(&uid)
-            .ok_or_else(|| anyhow!("Session {:#x?} not found!", uid))
-            .unwrap()
-            .down;
-        (
-            down_sess.tcp_in.clone().lock_owned(),
-            down_sess.stream_valve.clone(),
-        )
+    query: web::Query<DownloadQuery>,
+    payload: web::Payload,
+) -> HttpResponse {
+    let Some((uid, part)) = manager.find(&uid_s, |x| DownPart::new(&manager, x)).await else {
+        return decoy::respond(decoy, req, payload).await;
     };
-    let stream = WrapperBuilder {
-        guard: guard.await,
-        fr_builder: |a| FramedRead::new(a, BytesCodec::new()),
+    if part.eof.load(Ordering::Relaxed) {
+        manager.half_close(uid, &part.open_directions, DOWN).await;
+        return HttpResponse::Gone().finish();
     }
-    .build();
-    let stream = valve.wrap(stream);
-    //BytesMut -> Bytes
-    return HttpResponse::Ok().streaming(stream.map_ok(Into::into));
+    HttpResponse::Ok()
+        .content_type(part.encoding.content_type())
+        .streaming(stream_down(manager, uid, part, query.into_inner()).await)
 }
 
-#[get("/close/{uid_s}")]
-async fn close(manager: web::Data<ExitSessionManager>, uid_s: web::Path<String>) -> impl Responder {
-    let uid = Uuid::parse_str(&uid_s).unwrap();
-    println!("Close session {uid:#x?}");
-    let mut guard = manager.sessions.write().await;
-    let sess = guard
-        .remove(&uid)
-        .ok_or_else(|| anyhow!("Session with ID {uid:#x?} not found"))
-        .unwrap();
-    sess.down.stop_stream.cancel();
-    sess.up.stop_copy.cancel();
+/// Both directions in one request, the response streaming while the body still comes in.
+/// The response ends once both directions did, as the body is cut off with it.
+#[post("/stream/{uid_s}")]
+async fn duplex(
+    manager: web::Data<ExitSessionManager>,
+    decoy: web::Data<Decoy>,
+    req: HttpRequest,
+    uid_s: web::Path<String>,
+    payload: web::Payload,
+) -> HttpResponse {
+    let parts = |x: &ExitSession| (UpPart::new(&manager, x), DownPart::new(&manager, x));
+    let Some((uid, (up, down))) = manager.find(&uid_s, parts).await else {
+        return decoy::respond(decoy, req, payload).await;
+    };
+    // the payload is tied to this worker
+    let up = actix_web::rt::spawn(copy_up(manager.clone(), uid, up, payload, true));
+    // HTTP/2 reads on after the target's EOF ended the response,
+    // HTTP/1.1 would close the connection with the request body unread
+    let wait_up = req.version() != Version::HTTP_2;
+    let up = futures::stream::once(async move {
+        if wait_up {
+            let _ = up.await;
+        }
+        None
+    });
+    let content_type = down.encoding.content_type();
+    let down = stream_down(manager, uid, down, DownloadQuery::default()).await;
     HttpResponse::Ok()
+        .content_type(content_type)
+        .streaming(down.chain(up.filter_map(futures::future::ready)))
+}
+
+/// How the entry saw the session end, if it knows.
+#[derive(Debug, serde::Deserialize)]
+struct CloseQuery {
+    reason: Option<CloseReason>,
+}
+
+/// Answers with the reason the exit settled on, for the entry to mirror.
+#[get("/close/{uid_s}")]
+async fn close(
+    manager: web::Data<ExitSessionManager>,
+    decoy: web::Data<Decoy>,
+    req: HttpRequest,
+    uid_s: web::Path<String>,
+    query: web::Query<CloseQuery>,
+    payload: web::Payload,
+) -> HttpResponse {
+    let uid = Uuid::parse_str(&uid_s).ok();
+    if let Some(uid) = uid {
+        let reason = query.reason.unwrap_or(CloseReason::Fin);
+        let reason = match manager.close(uid, reason).await {
+            Some(x) => Some(x),
+            None => manager.take_ended(uid),
+        };
+        if let Some(reason) = reason {
+            return HttpResponse::Ok().body(reason.as_str());
+        }
+    }
+    decoy::respond(decoy, req, payload).await
+}
+
+/// Answers once the session ended, with the reason.
+/// For the entry to learn about it, and tear down, before its transfers notice.
+#[get("/wait/{uid_s}")]
+async fn wait_close(
+    manager: web::Data<ExitSessionManager>,
+    decoy: web::Data<Decoy>,
+    req: HttpRequest,
+    uid_s: web::Path<String>,
+    payload: web::Payload,
+) -> HttpResponse {
+    let uid = Uuid::parse_str(&uid_s).ok();
+    if let Some(uid) = uid {
+        let sess = manager
+            .sessions
+            .read()
+            .await
+            .get(&uid)
+            .map(|x| (x.ended.clone(), x.cause.clone()));
+        if let Some((ended, cause)) = sess {
+            ended.cancelled().await;
+            return HttpResponse::Ok().body(cause.get().unwrap().as_str());
+        }
+        // left for `/close` to take
+        if let Some(reason) = manager.ended.lock().unwrap().iter().find(|x| x.0 == uid) {
+            return HttpResponse::Ok().body(reason.1.as_str());
+        }
+    }
+    decoy::respond(decoy, req, payload).await
+}
+
+#[get("/healthz")]
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok().body("ok")
+}
+
+#[get("/readyz")]
+async fn readyz(manager: web::Data<ExitSessionManager>) -> impl Responder {
+    const TARGET_TIMEOUT: Duration = Duration::from_secs(2);
+
+    if manager.draining.load(Ordering::Relaxed) {
+        return HttpResponse::ServiceUnavailable().body("draining");
+    }
+    if manager.admission.is_saturated() {
+        return HttpResponse::ServiceUnavailable().body("session limit reached");
+    }
+    if let Endpoint::Command(_) | Endpoint::Udp(_) = manager.target_addr {
+        // nothing to reach, and not worth spawning it
+        return HttpResponse::Ok().body("ready");
+    }
+    let cause = CloseCause::default();
+    let probe = async {
+        let mut conn = connect_target(&manager.target_addr, None, &cause).await?;
+        // a health check of our own, which targets expecting the header would log as broken
+        if let Some(version) = manager.options.proxy_protocol {
+            let header = proxy_protocol::header(version, None);
+            conn.write.write_all(&header).await?;
+        }
+        std::io::Result::Ok(())
+    };
+    match tokio::time::timeout(TARGET_TIMEOUT, probe).await {
+        Ok(Ok(())) => HttpResponse::Ok().body("ready"),
+        Ok(Err(x)) => HttpResponse::ServiceUnavailable().body(format!("target unreachable: {x}")),
+        Err(_) => HttpResponse::ServiceUnavailable().body("target unreachable: timeout"),
+    }
+}
+
+/// The tunnel endpoints with their sessions, for mounting into an existing actix-web app:
+///
+/// ```no_run
+/// # use tcp_over_http::exit::{ExitOptions, ExitService};
+/// # use tcp_over_http::Endpoint;
+/// # use actix_web::{web, App, HttpServer};
+/// # async fn run() -> anyhow::Result<()> {
+/// let target = Endpoint::Unix("/run/postgresql/.s.PGSQL.5432".into());
+/// let exit = ExitService::new(target, ExitOptions::default())?;
+/// HttpServer::new(move || {
+///     App::new().service(web::scope("/tunnel").configure(|cfg| exit.configure(cfg)))
+/// })
+/// .bind("0.0.0.0:8080")?
+/// .run()
+/// .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ExitService {
+    manager: web::Data<ExitSessionManager>,
+    decoy: web::Data<Decoy>,
 }
 
-pub fn main(bind_addr: &[SocketAddr], target_addr: Vec<SocketAddr>) -> (Vec<SocketAddr>, Server) {
-    let session_manager = web::Data::new(ExitSessionManager::new(target_addr));
+impl ExitService {
+    /// Sessions are shared between all clones, so create this once, outside the app factory.
+    ///
+    /// # Errors
+    /// If the options contradict each other, e.g. `admin` without an `admin_token`.
+    pub fn new(target_addr: Endpoint, options: ExitOptions) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            !options.admin || options.admin_token.is_some(),
+            "The admin endpoints need an --admin-token."
+        );
+        let rates = [options.session_rate, options.global_rate];
+        anyhow::ensure!(
+            !rates.contains(&Some(ByteSize(0))) && options.open_rate != Some(0),
+            "Rate limits must be more than 0."
+        );
+        let decoy = web::Data::new(Decoy::new(&options));
+        Ok(Self {
+            manager: web::Data::new(ExitSessionManager::new(target_addr, options)),
+            decoy,
+        })
+    }
+
+    /// Registers the endpoints below `path_prefix` of the options,
+    /// relative to the scope this is called in.
+    /// TLS options are up to the surrounding server.
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        let admin = self
+            .manager
+            .options
+            .admin_token
+            .clone()
+            .filter(|_| self.manager.options.admin);
+        let tunnel = |cfg: &mut web::ServiceConfig| {
+            cfg.service(open)
+                .service(upload)
+                .service(download)
+                .service(duplex)
+                .service(close)
+                .service(wait_close);
+        };
+        let tunnel_token = self.manager.options.tunnel_token.clone();
+        let routes = move |cfg: &mut web::ServiceConfig| {
+            // first, as the tunnel's scope below swallows whatever comes after it
+            if let Some(token) = admin.clone() {
+                admin::configure(cfg, token);
+            }
+            // without the tunnel token, as load balancers probe them
+            cfg.service(healthz).service(readyz);
+            match tunnel_token.clone() {
+                None => tunnel(cfg),
+                // requests without it fall through to the decoy
+                Some(token) => {
+                    let guard =
+                        guard::fn_guard(move |ctx| has_tunnel_token(ctx.head().headers(), &token));
+                    cfg.service(web::scope("").guard(guard).configure(tunnel));
+                }
+            }
+        };
+        cfg.app_data(self.manager.clone())
+            .app_data(self.decoy.clone());
+        // an empty scope would swallow all routes registered after it
+        match self.manager.options.path_prefix.trim_matches('/') {
+            "" => routes(cfg),
+            x => {
+                cfg.service(web::scope(&format!("/{x}")).configure(routes));
+            }
+        }
+    }
+}
+
+pub fn main(
+    bind_addr: &Endpoint,
+    target_addr: Endpoint,
+    options: ExitOptions,
+) -> (Endpoint, Server) {
+    let tls = match tls::server_config(&options.tls) {
+        Ok(x) => x,
+        Err(x) => {
+            eprintln!("Could not load TLS configuration: {x:#}");
+            panic!();
+        }
+    };
+    let windows = match Http2Windows::new(&options) {
+        Ok(x) => x,
+        Err(x) => {
+            eprintln!("{x:#}");
+            panic!();
+        }
+    };
+    let h2c = options.h2c;
+    let service = match ExitService::new(target_addr, options) {
+        Ok(x) => x,
+        Err(x) => {
+            eprintln!("{x:#}");
+            panic!();
+        }
+    };
     #[cfg(test)]
     {
-        *test::ARC.try_lock().unwrap() = Some(session_manager.clone());
+        *test::ARC.try_lock().unwrap() = Some(service.manager.clone());
     }
-    let x = HttpServer::new(move || {
+    let app = move || {
         App::new()
-            .app_data(session_manager.clone())
             //.app_data(web::PayloadConfig::new(1024 * 1024))
-            .service(open)
-            .service(upload)
-            .service(download)
-            .service(close)
-    })
-    .bind(bind_addr)
+            .configure(|cfg| service.configure(cfg))
+            .default_service(web::to(decoy::respond))
+    };
+    if let (Endpoint::Unix(_), true) = (bind_addr, h2c) {
+        eprintln!("--h2c is not supported on a Unix socket, it serves HTTP/1.1 only.");
+        panic!();
+    }
+    // actix-web's server does not take the HTTP/2 windows, so these build their own
+    if let (Endpoint::Tcp(addrs), Some(tls)) = (bind_addr, &tls) {
+        return serve_tls(addrs, tls.clone(), windows, app);
+    }
+    if let (Endpoint::Tcp(addrs), true) = (bind_addr, h2c) {
+        return serve_h2c(addrs, windows, app);
+    }
+    let x = HttpServer::new(app);
+    let x = match (bind_addr, tls) {
+        (Endpoint::Tcp(addrs), _) => x.bind(addrs.as_slice()),
+        #[cfg(unix)]
+        (Endpoint::Unix(path), None) => {
+            remove_stale_socket(path);
+            x.bind_uds(path)
+        }
+        #[cfg(not(unix))]
+        (Endpoint::Unix(_), None) => {
+            eprintln!("Unix sockets are not supported on this platform.");
+            panic!();
+        }
+        (Endpoint::Unix(_), Some(_)) => {
+            eprintln!(
+                "TLS is not supported on a Unix socket, terminate it in front of the exit node."
+            );
+            panic!();
+        }
+        (Endpoint::Udp(_) | Endpoint::Command(_), _) => {
+            eprintln!("Can not listen on {bind_addr}.");
+            panic!();
+        }
+    }
     .unwrap();
-    let bound = x.addrs();
-    println!("Listening on {bound:?}");
+    let bound = match bind_addr {
+        Endpoint::Tcp(_) => Endpoint::Tcp(x.addrs()),
+        Endpoint::Udp(_) | Endpoint::Unix(_) | Endpoint::Command(_) => bind_addr.clone(),
+    };
+    println!("Listening on {bound}");
     return (bound, x.run());
 }
 
+/// The exit's HTTP/2 windows, limiting the upload bytes in flight. actix's defaults if `None`.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Http2Windows {
+    stream: Option<u32>,
+    connection: Option<u32>,
+}
+
+impl Http2Windows {
+    fn new(options: &ExitOptions) -> anyhow::Result<Self> {
+        let window = |x: Option<ByteSize>| x.map(http2_window).transpose();
+        Ok(Self {
+            stream: window(options.http2_stream_window)?,
+            connection: window(options.http2_connection_window)?,
+        })
+    }
+}
+
+/// Start of every HTTP/2 connection. No HTTP/1 request starts with `PRI`.
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// How long a new connection has to send its first bytes before it is taken for HTTP/1.
+const PREFACE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A new connection with the bytes read to tell HTTP/2 from HTTP/1 put back in front.
+struct Sniffed {
+    head: Vec<u8>,
+    io: TcpStream,
+}
+
+impl Sniffed {
+    /// Reads until the HTTP/2 preface is complete or ruled out, returning whether it was sent.
+    async fn new(mut io: TcpStream) -> (bool, Self) {
+        let mut head = Vec::with_capacity(H2_PREFACE.len());
+        let read = async {
+            let mut buf = [0; H2_PREFACE.len()];
+            while head.len() < H2_PREFACE.len() {
+                let len = io
+                    .read(&mut buf[..H2_PREFACE.len() - head.len()])
+                    .await
+                    .unwrap_or(0);
+                head.extend_from_slice(&buf[..len]);
+                if len == 0 || !H2_PREFACE.starts_with(&head) {
+                    return false;
+                }
+            }
+            true
+        };
+        let h2 = tokio::time::timeout(PREFACE_TIMEOUT, read)
+            .await
+            .unwrap_or(false);
+        (h2, Self { head, io })
+    }
+}
+
+impl AsyncRead for Sniffed {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.head.is_empty() {
+            return Pin::new(&mut self.io).poll_read(cx, buf);
+        }
+        let len = self.head.len().min(buf.remaining());
+        buf.put_slice(&self.head[..len]);
+        self.head.drain(..len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Sniffed {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+/// Serves `app` over HTTP/1.1, or HTTP/2 to connections starting with its preface.
+pub(crate) fn serve_h2c<F, T, B>(
+    addrs: &[SocketAddr],
+    windows: Http2Windows,
+    app: F,
+) -> (Endpoint, Server)
+where
+    F: Fn() -> App<T> + Send + Clone + 'static,
+    T: ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<B>,
+            Error = actix_web::Error,
+            InitError = (),
+        > + 'static,
+    B: MessageBody + 'static,
+{
+    let service = move || {
+        let app = app.clone();
+        fn_factory(move || {
+            let local_addr = |io: &Sniffed, ext: &mut Extensions| {
+                if let Ok(x) = io.io.local_addr() {
+                    ext.insert(LocalAddr(x));
+                }
+            };
+            let h1 = HttpService::build()
+                .on_connect_ext(local_addr)
+                .h1(map_config(app(), |()| AppConfig::default()));
+            let mut h2 = HttpService::build().on_connect_ext(local_addr);
+            if let Some(x) = windows.stream {
+                h2 = h2.h2_initial_window_size(x);
+            }
+            if let Some(x) = windows.connection {
+                h2 = h2.h2_initial_connection_window_size(x);
+            }
+            let h2 = h2.h2(map_config(app(), |()| AppConfig::default()));
+            async move {
+                let h1 = Rc::new(h1.new_service(()).await?);
+                let h2 = Rc::new(h2.new_service(()).await?);
+                Ok::<_, ()>(fn_service(move |io: TcpStream| {
+                    let (h1, h2) = (h1.clone(), h2.clone());
+                    async move {
+                        let peer = io.peer_addr().ok();
+                        match Sniffed::new(io).await {
+                            (true, io) => h2.call((io, peer)).await,
+                            (false, io) => h1.call((io, peer)).await,
+                        }
+                    }
+                }))
+            }
+        })
+    };
+    let mut server = Server::build();
+    let mut bound = Vec::new();
+    for addr in addrs {
+        let listener = std::net::TcpListener::bind(addr).unwrap();
+        bound.push(listener.local_addr().unwrap());
+        server = server.listen("h2c", listener, service.clone()).unwrap();
+    }
+    let bound = Endpoint::Tcp(bound);
+    println!("Listening on {bound}");
+    return (bound, server.run());
+}
+
+/// Serves `app` over TLS, with HTTP/2 for clients choosing it through ALPN.
+fn serve_tls<F, T, B>(
+    addrs: &[SocketAddr],
+    tls: ServerConfig,
+    windows: Http2Windows,
+    app: F,
+) -> (Endpoint, Server)
+where
+    F: Fn() -> App<T> + Send + Clone + 'static,
+    T: ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<B>,
+            Error = actix_web::Error,
+            InitError = (),
+        > + 'static,
+    B: MessageBody + 'static,
+{
+    let service = move || {
+        let local_addr = |io: &TlsStream<TcpStream>, ext: &mut Extensions| {
+            if let Ok(x) = io.get_ref().0.local_addr() {
+                ext.insert(LocalAddr(x));
+            }
+        };
+        let mut http = HttpService::build().on_connect_ext(local_addr);
+        if let Some(x) = windows.stream {
+            http = http.h2_initial_window_size(x);
+        }
+        if let Some(x) = windows.connection {
+            http = http.h2_initial_connection_window_size(x);
+        }
+        http.finish(map_config(app(), |()| AppConfig::default()))
+            .rustls(tls.clone())
+    };
+    let mut server = Server::build();
+    let mut bound = Vec::new();
+    for addr in addrs {
+        let listener = std::net::TcpListener::bind(addr).unwrap();
+        bound.push(listener.local_addr().unwrap());
+        server = server.listen("tls", listener, service.clone()).unwrap();
+    }
+    let bound = Endpoint::Tcp(bound);
+    println!("Listening on {bound}");
+    return (bound, server.run());
+}
+
 #[cfg(test)]
 pub(crate) mod test {
     use super::ExitSessionManager;
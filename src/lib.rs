@@ -0,0 +1,192 @@
+//#![allow(warnings)]
+#![cfg_attr(
+    not(feature = "rustc_stable"),
+    feature(
+        core_intrinsics,
+        auto_traits,
+        negative_impls,
+        panic_internals,
+        panic_info_message
+    )
+)]
+#![allow(clippy::needless_return)]
+#![warn(clippy::pedantic)]
+// setup errors are reported by panicking, see `init_panic_hook`
+#![allow(clippy::missing_panics_doc)]
+
+use anyhow::anyhow;
+use reqwest::Url;
+use std::path::PathBuf;
+use std::{convert::Infallible, net::SocketAddr, str::FromStr};
+use tokio::net::lookup_host;
+
+pub mod admin;
+mod close;
+mod decoy;
+mod encoding;
+pub mod entry;
+pub mod exit;
+mod limit;
+mod pool;
+mod proxy_protocol;
+mod tls;
+mod udp;
+mod upstream;
+
+#[cfg(test)]
+mod tests;
+
+#[cfg(not(feature = "rustc_stable"))]
+mod error;
+
+pub(crate) fn join_url<'a>(base: &Url, path: impl IntoIterator<Item = &'a str>) -> Url {
+    //url::ParseError
+    assert!(base.path().ends_with('/'));
+    let mut it = path.into_iter();
+    let first = it.next().unwrap();
+    let mut next = it.next();
+    if next.is_some() {
+        assert!(first.ends_with('/'));
+    }
+    let mut last = base.join(first).unwrap();
+    while let Some(x) = next {
+        last = last.join(x).unwrap();
+        next = it.next();
+        if next.is_some() {
+            assert!(x.ends_with('/'));
+        }
+    }
+    return last;
+}
+
+/// Appends a missing `/`, as the base of [`join_url`] would lose its last path segment.
+pub(crate) fn dir_url(mut url: Url) -> Url {
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+    url
+}
+
+/// Compares in constant time, not to leak how much of a secret matched.
+pub(crate) fn same_secret(given: &[u8], secret: &str) -> bool {
+    given.len() == secret.len()
+        && given
+            .iter()
+            .zip(secret.as_bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+/// Host and port, resolved when starting up, `udp:host:port` for datagrams,
+/// `unix:/path` of a Unix socket or, as target only, `cmd:<command line>` to run per session.
+#[derive(Clone, Debug)]
+pub struct ResolveAddr(String); //lookup_host
+impl FromStr for ResolveAddr {
+    type Err = Infallible;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.to_owned()))
+    }
+}
+impl ResolveAddr {
+    pub async fn resolve(self) -> Endpoint {
+        if let Some(path) = self.0.strip_prefix("unix:") {
+            return Endpoint::Unix(path.into());
+        }
+        if let Some(command) = self.0.strip_prefix("cmd:") {
+            return Endpoint::Command(command.to_owned());
+        }
+        let (host, udp) = match self.0.strip_prefix("udp:") {
+            Some(x) => (x, true),
+            None => (self.0.as_str(), false),
+        };
+        let addrs = lookup_host(host)
+            .await
+            .map_err(|e| anyhow!("{self:#?} - {e:#?}"))
+            .unwrap()
+            .collect::<Vec<_>>();
+        if udp {
+            Endpoint::Udp(addrs)
+        } else {
+            Endpoint::Tcp(addrs)
+        }
+    }
+}
+
+/// Where to connect to or listen on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    Tcp(Vec<SocketAddr>),
+    /// Datagrams framed into a session, see the `udp` module.
+    Udp(Vec<SocketAddr>),
+    Unix(PathBuf),
+    /// Run by `sh -c`, its stdin and stdout are the connection.
+    Command(String),
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(x) => write!(f, "{x:?}"),
+            Self::Udp(x) => write!(f, "udp:{x:?}"),
+            Self::Unix(x) => write!(f, "unix:{}", x.display()),
+            Self::Command(x) => write!(f, "cmd:{x}"),
+        }
+    }
+}
+
+/// Removes a socket file left behind by a previous run, so it can be bound again.
+/// One still accepting connections is left alone, binding then fails as it is in use.
+/// So does it after a failed removal, which is only logged here.
+#[cfg(unix)]
+pub(crate) fn remove_stale_socket(path: &std::path::Path) {
+    use std::os::unix::{fs::FileTypeExt, net::UnixStream};
+
+    if std::fs::symlink_metadata(path).is_ok_and(|x| x.file_type().is_socket())
+        && UnixStream::connect(path)
+            .is_err_and(|x| x.kind() == std::io::ErrorKind::ConnectionRefused)
+    {
+        if let Err(x) = std::fs::remove_file(path) {
+            eprintln!("Could not remove stale socket {}: {x}", path.display());
+        }
+    }
+}
+
+/// Exits the process on any panic, also one in a spawned task.
+pub fn init_panic_hook() {
+    static ONCE_GUARD: std::sync::Once = std::sync::Once::new();
+    ONCE_GUARD.call_once(|| {
+        let org = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            org(info);
+            std::process::exit(101);
+        }));
+    });
+}
+
+use std::pin::Pin;
+use tokio::io::AsyncRead;
+use tokio::sync::OwnedMutexGuard;
+use tokio_util::codec::{BytesCodec, FramedRead};
+
+#[ouroboros::self_referencing]
+pub(crate) struct Wrapper<R: 'static> {
+    guard: OwnedMutexGuard<R>,
+    #[borrows(mut guard)]
+    #[not_covariant]
+    fr: FramedRead<&'this mut R, BytesCodec>,
+}
+
+impl<R: AsyncRead + Unpin + 'static> futures::Stream for Wrapper<R> {
+    type Item = Result<bytes::BytesMut, std::io::Error>;
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.with_fr_mut(|fr| Pin::new(fr).poll_next(cx))
+    }
+}
+
+pub(crate) type Artex<T> = std::sync::Arc<tokio::sync::Mutex<T>>;
+pub(crate) fn artex<T>(t: T) -> Artex<T> {
+    std::sync::Arc::new(tokio::sync::Mutex::new(t))
+}
@@ -0,0 +1,313 @@
+use halfbrown::HashMap as Map;
+use std::hash::Hash;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Byte count from the command line, e.g. `512`, `64K`, `10M` or `1G` (powers of 1024).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (num, shift) = match s.char_indices().last() {
+            Some((i, 'k' | 'K')) => (&s[..i], 10),
+            Some((i, 'm' | 'M')) => (&s[..i], 20),
+            Some((i, 'g' | 'G')) => (&s[..i], 30),
+            _ => (s, 0),
+        };
+        let num: u64 = num.parse().map_err(|x| format!("{s:?}: {x}"))?;
+        num.checked_mul(1 << shift)
+            .map(Self)
+            .ok_or_else(|| format!("{s:?} is too large"))
+    }
+}
+
+impl ByteSize {
+    /// Parser for rates, where 0 would stall every transfer.
+    pub(crate) fn nonzero(s: &str) -> Result<Self, String> {
+        match s.parse()? {
+            Self(0) => Err("must be more than 0".to_owned()),
+            x => Ok(x),
+        }
+    }
+}
+
+/// Token bucket: refills `rate` tokens per second up to `burst`.
+///
+/// Taking more than is available puts the bucket into debt,
+/// so a chunk bigger than `burst` still passes, followed by a longer pause.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    // tokens, last refill
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    #[allow(clippy::cast_precision_loss)]
+    pub(crate) fn new(rate: u64, burst: u64) -> Self {
+        assert!(rate > 0 && burst > 0, "token bucket without tokens");
+        let burst = burst as f64;
+        Self {
+            rate: rate as f64,
+            burst,
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// Bucket with a burst of one second worth of tokens.
+    pub(crate) fn per_second(rate: u64) -> Self {
+        Self::new(rate, rate)
+    }
+
+    fn refill(&self, state: &mut (f64, Instant)) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.1).as_secs_f64();
+        state.0 = (state.0 + elapsed * self.rate).min(self.burst);
+        state.1 = now;
+    }
+
+    /// Takes `n` tokens, returning how long to wait until the debt is paid.
+    #[allow(clippy::cast_precision_loss)]
+    fn reserve(&self, n: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state);
+        state.0 -= n as f64;
+        if state.0 >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.0 / self.rate)
+        }
+    }
+
+    pub(crate) async fn take(&self, n: usize) {
+        let wait = self.reserve(n);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Takes `n` tokens if available, otherwise returns how long until they are.
+    #[allow(clippy::cast_precision_loss)]
+    pub(crate) fn try_take(&self, n: usize) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state);
+        let missing = n as f64 - state.0;
+        if missing <= 0.0 {
+            state.0 -= n as f64;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(missing / self.rate))
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state);
+        state.0 >= self.burst
+    }
+}
+
+/// All buckets a transfer direction has to pass, e.g. the session's and the global one.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Throttle(Vec<Arc<TokenBucket>>);
+
+impl Throttle {
+    pub(crate) fn new<'a>(buckets: impl IntoIterator<Item = &'a Option<Arc<TokenBucket>>>) -> Self {
+        Self(buckets.into_iter().flatten().cloned().collect())
+    }
+
+    pub(crate) async fn take(&self, n: usize) {
+        for bucket in &self.0 {
+            bucket.take(n).await;
+        }
+    }
+
+    /// Takes `n` tokens without waiting, what comes next waits for the debt instead.
+    pub(crate) fn charge(&self, n: usize) {
+        for bucket in &self.0 {
+            bucket.reserve(n);
+        }
+    }
+}
+
+/// One token bucket per key, e.g. per client IP.
+#[derive(Debug)]
+pub(crate) struct KeyedLimiter<K> {
+    rate: u64,
+    buckets: Mutex<Map<K, TokenBucket>>,
+}
+
+impl<K: Hash + Eq> KeyedLimiter<K> {
+    /// Forget idle keys once there are this many.
+    const PRUNE_AT: usize = 1024;
+
+    pub(crate) fn per_second(rate: u64) -> Self {
+        Self {
+            rate,
+            buckets: Mutex::new(Map::new()),
+        }
+    }
+
+    pub(crate) fn check(&self, key: K) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= Self::PRUNE_AT {
+            buckets.retain(|_, x| !x.is_full());
+        }
+        buckets
+            .entry(key)
+            .or_insert_with(|| TokenBucket::per_second(self.rate))
+            .try_take(1)
+    }
+}
+
+#[derive(Debug, Default)]
+struct AdmissionState {
+    total: usize,
+    per_client: Map<String, usize>,
+}
+
+/// Caps concurrent sessions, overall and per client.
+#[derive(Debug)]
+pub(crate) struct Admission {
+    max_total: Option<usize>,
+    max_per_client: Option<usize>,
+    /// How long to wait for a free slot, `None` rejects right away.
+    queue: Option<Duration>,
+    state: Arc<Mutex<AdmissionState>>,
+    released: Arc<Notify>,
+}
+
+/// Slot of an admitted session, freed on drop.
+#[derive(Debug)]
+pub(crate) struct Ticket {
+    client: String,
+    state: Arc<Mutex<AdmissionState>>,
+    released: Arc<Notify>,
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.total -= 1;
+        let count = state.per_client.get_mut(&self.client).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            state.per_client.remove(&self.client);
+        }
+        drop(state);
+        self.released.notify_waiters();
+    }
+}
+
+impl Admission {
+    pub(crate) fn new(
+        max_total: Option<usize>,
+        max_per_client: Option<usize>,
+        queue: Option<Duration>,
+    ) -> Self {
+        Self {
+            max_total,
+            max_per_client,
+            queue,
+            state: Arc::default(),
+            released: Arc::default(),
+        }
+    }
+
+    pub(crate) fn is_saturated(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        self.max_total.is_some_and(|max| state.total >= max)
+    }
+
+    fn try_admit(&self, client: &str) -> Option<Ticket> {
+        let mut state = self.state.lock().unwrap();
+        if self.max_total.is_some_and(|max| state.total >= max) {
+            return None;
+        }
+        let count = state.per_client.get(client).copied().unwrap_or(0);
+        if self.max_per_client.is_some_and(|max| count >= max) {
+            return None;
+        }
+        state.total += 1;
+        state.per_client.insert(client.to_owned(), count + 1);
+        Some(Ticket {
+            client: client.to_owned(),
+            state: self.state.clone(),
+            released: self.released.clone(),
+        })
+    }
+
+    /// Waits up to the queue timeout for a free slot.
+    pub(crate) async fn admit(&self, client: &str) -> Option<Ticket> {
+        let deadline = Instant::now() + self.queue.unwrap_or_default();
+        loop {
+            // register before checking, so a release in between is not missed
+            let released = self.released.notified();
+            if let Some(ticket) = self.try_admit(client) {
+                return Some(ticket);
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() || tokio::time::timeout(left, released).await.is_err() {
+                return None;
+            }
+        }
+    }
+}
+
+#[test]
+fn byte_size() {
+    assert_eq!("512".parse(), Ok(ByteSize(512)));
+    assert_eq!("64k".parse(), Ok(ByteSize(64 * 1024)));
+    assert_eq!("10M".parse(), Ok(ByteSize(10 * 1024 * 1024)));
+    assert_eq!("1G".parse(), Ok(ByteSize(1024 * 1024 * 1024)));
+    "M".parse::<ByteSize>().unwrap_err();
+    "1T".parse::<ByteSize>().unwrap_err();
+    assert_eq!(ByteSize::nonzero("1k"), Ok(ByteSize(1024)));
+    ByteSize::nonzero("0K").unwrap_err();
+}
+
+#[test]
+fn token_bucket() {
+    let bucket = TokenBucket::new(1000, 100);
+    assert_eq!(bucket.try_take(100), Ok(()));
+    let wait = bucket.try_take(50).unwrap_err();
+    assert!(wait <= Duration::from_millis(50) && wait > Duration::from_millis(40));
+    // debt
+    assert!(bucket.reserve(1000) > Duration::from_millis(900));
+}
+
+#[test]
+fn admission() {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        let admission = Admission::new(Some(3), Some(2), None);
+        let a1 = admission.admit("a").await.unwrap();
+        let _a2 = admission.admit("a").await.unwrap();
+        assert!(admission.admit("a").await.is_none());
+        let _b1 = admission.admit("b").await.unwrap();
+        assert!(admission.is_saturated());
+        assert!(admission.admit("c").await.is_none());
+        drop(a1);
+        assert!(!admission.is_saturated());
+        let _c1 = admission.admit("c").await.unwrap();
+
+        let admission = Arc::new(Admission::new(Some(1), None, Some(Duration::from_secs(5))));
+        let first = admission.admit("a").await.unwrap();
+        let queued = tokio::spawn({
+            let admission = admission.clone();
+            async move { admission.admit("b").await.is_some() }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(first);
+        assert!(queued.await.unwrap());
+    });
+}
@@ -0,0 +1,113 @@
+// Sessions the entry opens before clients arrive. The exit connects to the target on
+// `/open`, so a pooled session is bridged to an accepted client without a round trip.
+
+use crate::entry::{close_session, init_http_session, ExitNode};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+/// Pause after the exit refused a session or could not reach the target.
+const RETRY: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub(crate) struct SessionPool {
+    size: usize,
+    /// `None` keeps unused sessions open.
+    idle: Option<Duration>,
+    /// With the time each was opened, oldest first.
+    sessions: Mutex<VecDeque<(Uuid, Instant)>>,
+    taken: Notify,
+}
+
+impl SessionPool {
+    pub(crate) fn new(size: usize, idle: Option<Duration>) -> Self {
+        Self {
+            size,
+            idle,
+            sessions: Mutex::new(VecDeque::with_capacity(size)),
+            taken: Notify::new(),
+        }
+    }
+
+    fn expired(&self, opened: Instant) -> bool {
+        self.idle.is_some_and(|x| opened.elapsed() >= x)
+    }
+
+    /// The newest session, unless even that one expired.
+    pub(crate) fn take(&self) -> Option<Uuid> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let &(uid, opened) = sessions.back()?;
+        if self.expired(opened) {
+            return None;
+        }
+        sessions.pop_back();
+        self.taken.notify_one();
+        Some(uid)
+    }
+}
+
+/// Keeps the pool of `exit` full, closing sessions that went unused for too long.
+pub(crate) async fn fill(exit: Arc<ExitNode>) -> Infallible {
+    let pool = exit.pool.as_ref().unwrap();
+    loop {
+        let expired: Vec<_> = {
+            let mut sessions = pool.sessions.lock().unwrap();
+            let fresh = sessions
+                .iter()
+                .position(|&(_, opened)| !pool.expired(opened))
+                .unwrap_or(sessions.len());
+            sessions.drain(..fresh).map(|(uid, _)| uid).collect()
+        };
+        for uid in expired {
+            close_session(&exit, uid, None).await;
+        }
+        let (len, oldest) = {
+            let sessions = pool.sessions.lock().unwrap();
+            (sessions.len(), sessions.front().map(|&(_, opened)| opened))
+        };
+        if len < pool.size {
+            match init_http_session(&exit, None, None).await {
+                Ok((uid, _)) => {
+                    let mut sessions = pool.sessions.lock().unwrap();
+                    sessions.push_back((uid, Instant::now()));
+                }
+                Err(x) => {
+                    dbg!(x);
+                    tokio::time::sleep(RETRY).await;
+                }
+            }
+            continue;
+        }
+        let expiry = async {
+            match oldest.zip(pool.idle) {
+                Some((opened, idle)) => tokio::time::sleep_until(opened + idle).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            () = pool.taken.notified() => {}
+            () = expiry => {}
+        }
+    }
+}
+
+#[test]
+fn take_newest() {
+    let pool = SessionPool::new(2, Some(Duration::from_secs(30)));
+    assert_eq!(pool.take(), None);
+    let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+    let now = Instant::now();
+    pool.sessions.lock().unwrap().extend([(a, now), (b, now)]);
+    assert_eq!(pool.take(), Some(b));
+    assert_eq!(pool.take(), Some(a));
+    assert_eq!(pool.take(), None);
+
+    let stale = SessionPool::new(2, Some(Duration::ZERO));
+    stale.sessions.lock().unwrap().push_back((a, now));
+    assert_eq!(stale.take(), None);
+    assert_eq!(stale.sessions.lock().unwrap().len(), 1);
+}
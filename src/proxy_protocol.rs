@@ -0,0 +1,105 @@
+// https://www.haproxy.org/download/2.6/doc/proxy-protocol.txt
+
+use std::net::{IpAddr, SocketAddr};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProxyProtocol {
+    /// Human readable header.
+    V1,
+    /// Binary header.
+    V2,
+}
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Brings both addresses into the same family, v4 gets mapped into v6 if needed.
+fn same_family(src: SocketAddr, dst: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let to_v6 = |x: SocketAddr| match x.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(ip.to_ipv6_mapped().into(), x.port()),
+        IpAddr::V6(_) => x,
+    };
+    if src.is_ipv4() == dst.is_ipv4() {
+        (src, dst)
+    } else {
+        (to_v6(src), to_v6(dst))
+    }
+}
+
+/// Header to send to the target before any payload.
+/// Without addresses (client unknown) the target is told to use the connection's own.
+pub(crate) fn header(version: ProxyProtocol, addrs: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    let addrs = addrs.map(|(src, dst)| same_family(src, dst));
+    match version {
+        ProxyProtocol::V1 => match addrs {
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+            Some((src, dst)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if src.is_ipv4() { "TCP4" } else { "TCP6" },
+                src.ip(),
+                dst.ip(),
+                src.port(),
+                dst.port(),
+            )
+            .into_bytes(),
+        },
+        ProxyProtocol::V2 => {
+            let mut out = V2_SIGNATURE.to_vec();
+            let Some((src, dst)) = addrs else {
+                // version 2, LOCAL; UNSPEC; no addresses
+                out.extend([0x20, 0x00, 0, 0]);
+                return out;
+            };
+            let mut body = Vec::with_capacity(36);
+            let family = match (src.ip(), dst.ip()) {
+                (IpAddr::V4(s), IpAddr::V4(d)) => {
+                    body.extend(s.octets());
+                    body.extend(d.octets());
+                    0x11
+                }
+                (IpAddr::V6(s), IpAddr::V6(d)) => {
+                    body.extend(s.octets());
+                    body.extend(d.octets());
+                    0x21
+                }
+                _ => unreachable!(),
+            };
+            body.extend(src.port().to_be_bytes());
+            body.extend(dst.port().to_be_bytes());
+            // version 2, PROXY; TCP over family
+            out.extend([0x21, family]);
+            out.extend(u16::try_from(body.len()).unwrap().to_be_bytes());
+            out.extend(body);
+            out
+        }
+    }
+}
+
+#[test]
+fn v1() {
+    let src = "192.0.2.1:56324".parse().unwrap();
+    let dst = "198.51.100.7:443".parse().unwrap();
+    assert_eq!(
+        header(ProxyProtocol::V1, Some((src, dst))),
+        b"PROXY TCP4 192.0.2.1 198.51.100.7 56324 443\r\n"
+    );
+    let dst = "[2001:db8::1]:443".parse().unwrap();
+    assert_eq!(
+        header(ProxyProtocol::V1, Some((src, dst))),
+        b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::1 56324 443\r\n"
+    );
+    assert_eq!(header(ProxyProtocol::V1, None), b"PROXY UNKNOWN\r\n");
+}
+
+#[test]
+fn v2() {
+    let src = "192.0.2.1:56324".parse().unwrap();
+    let dst = "198.51.100.7:443".parse().unwrap();
+    let h = header(ProxyProtocol::V2, Some((src, dst)));
+    assert_eq!(h[..12], V2_SIGNATURE);
+    assert_eq!(
+        h[12..],
+        [0x21, 0x11, 0, 12, 192, 0, 2, 1, 198, 51, 100, 7, 0xdc, 0x04, 0x01, 0xbb]
+    );
+    let h = header(ProxyProtocol::V2, None);
+    assert_eq!(h[12..], [0x20, 0x00, 0, 0]);
+}
@@ -1,9 +1,11 @@
 use crate::{
-    entry,
-    exit::{self, ExitSession, ExitSessionManager},
-    init_panic_hook, ResolveAddr,
+    entry::{self, BodyEncoding, EntryOptions, ExitNode},
+    exit::{self, ExitOptions, ExitService, ExitSession, ExitSessionManager},
+    init_panic_hook,
+    limit::ByteSize,
+    Endpoint, ResolveAddr,
 };
-use actix_web::web;
+use actix_web::{web, App, HttpServer};
 use halfbrown::HashMap;
 use itertools::Itertools;
 use rand::RngCore;
@@ -25,17 +27,30 @@ struct Persist {
     exit_conn: TcpStream,
 }
 
-async fn roundtrip() -> Persist {
+async fn roundtrip(http2: bool) -> Persist {
     let localhost = localhost().await;
 
     let target_listen = tokio::net::TcpListener::bind(localhost).await.unwrap();
-    let (exit_addr, f_exit) = exit::main(localhost, vec![target_listen.local_addr().unwrap()]);
+    let (exit_addr, f_exit) = exit::main(
+        &Endpoint::Tcp(localhost.to_vec()),
+        Endpoint::Tcp(vec![target_listen.local_addr().unwrap()]),
+        ExitOptions {
+            h2c: http2,
+            ..ExitOptions::default()
+        },
+    );
+    // the entry checks the exit's health before listening
+    let f_exit = tokio::spawn(f_exit);
 
-    let exit_addr = exit_addr.first().unwrap();
+    let exit_addr = tcp(exit_addr);
 
     let (entry_addr, f_entry) = entry::main(
-        localhost,
+        &Endpoint::Tcp(localhost.to_vec()),
         format!("http://{exit_addr}/").as_str().try_into().unwrap(),
+        EntryOptions {
+            http2,
+            ..EntryOptions::default()
+        },
     )
     .await;
 
@@ -47,7 +62,7 @@ async fn roundtrip() -> Persist {
             rand_buf
         };
         //connect to entry
-        let mut entry_conn = TcpStream::connect(entry_addr).await.unwrap();
+        let mut entry_conn = TcpStream::connect(tcp(entry_addr.clone())).await.unwrap();
         //send rand
         let join_send = async {
             entry_conn.write_all(&irand).await.unwrap();
@@ -140,8 +155,8 @@ fn test() {
             assert_eq!(Arc::strong_count(tcp_in), 2);
             let tcp_out = &sess.up.tcp_out;
             assert_eq!(Arc::strong_count(tcp_out), 2);
-            tcp_in.try_lock().unwrap_err();
-            tcp_out.try_lock().unwrap_err();
+            assert!(tcp_in.try_lock().is_err());
+            assert!(tcp_out.try_lock().is_err());
             assert!(!sess.up.stop_copy.is_cancelled());
             let tcp_in = tcp_in.clone();
             let tcp_out = tcp_out.clone();
@@ -157,8 +172,8 @@ fn test() {
             }
         };
 
-        let roundtrip = |rst| async move {
-            let conn = roundtrip().await;
+        let roundtrip = |rst, http2| async move {
+            let conn = roundtrip(http2).await;
             if rst {
                 conn.entry_conn.set_linger(Some(Duration::ZERO)).unwrap();
                 conn.exit_conn.set_linger(Some(Duration::ZERO)).unwrap();
@@ -166,16 +181,16 @@ fn test() {
             (conn.entry_conn, conn.exit_conn, assert().await)
         };
 
-        let (entry_conn, exit_conn, assert) = roundtrip(false).await;
+        let (entry_conn, exit_conn, assert) = roundtrip(false, false).await;
         drop((entry_conn, exit_conn));
         sleep(Duration::from_millis(100)).await;
         assert.await;
 
-        let roundtrip = || roundtrip(true);
+        let roundtrip_rst = || roundtrip(true, false);
 
         dbg!();
 
-        let (entry_conn, exit_conn, assert) = roundtrip().await;
+        let (entry_conn, exit_conn, assert) = roundtrip_rst().await;
         drop(entry_conn);
         sleep(Duration::from_millis(100)).await;
         assert.await;
@@ -183,7 +198,7 @@ fn test() {
 
         dbg!();
 
-        let (entry_conn, exit_conn, assert) = roundtrip().await;
+        let (entry_conn, exit_conn, assert) = roundtrip_rst().await;
         drop(exit_conn);
         sleep(Duration::from_millis(100)).await;
         assert.await;
@@ -191,20 +206,1287 @@ fn test() {
 
         dbg!();
 
-        let (entry_conn, exit_conn, assert) = roundtrip().await;
+        let (entry_conn, exit_conn, assert) = roundtrip_rst().await;
 
         drop((entry_conn, exit_conn));
         sleep(Duration::from_millis(100)).await;
         assert.await;
 
         dbg!();
+
+        // the same through `exit::main` over h2c
+        let (entry_conn, exit_conn, assert) = roundtrip(false, true).await;
+        drop((entry_conn, exit_conn));
+        sleep(Duration::from_millis(100)).await;
+        assert.await;
+    });
+}
+
+/// Exit node on localhost, without going through `exit::main`.
+async fn exit_node(target: Endpoint) -> Arc<ExitNode> {
+    exit_node_with(target, ExitOptions::default()).await
+}
+
+async fn exit_node_with(target: Endpoint, options: ExitOptions) -> Arc<ExitNode> {
+    let service = ExitService::new(target, options).unwrap();
+    let server = HttpServer::new(move || App::new().configure(|cfg| service.configure(cfg)))
+        .bind(localhost().await)
+        .unwrap();
+    let exit_addr = server.addrs()[0];
+    tokio::spawn(server.run());
+    let url = format!("http://{exit_addr}/").parse().unwrap();
+    Arc::new(ExitNode::new(url, &EntryOptions::default()).unwrap())
+}
+
+#[test]
+fn admin() {
+    use crate::admin::{self, AdminCommand};
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[clap(subcommand)]
+        command: AdminCommand,
+    }
+
+    RT.block_on(async {
+        let target_listen = tokio::net::TcpListener::bind(localhost().await)
+            .await
+            .unwrap();
+        let options = ExitOptions {
+            admin: true,
+            admin_token: Some("secret".to_owned()),
+            ..ExitOptions::default()
+        };
+        let target = Endpoint::Tcp(vec![target_listen.local_addr().unwrap()]);
+        // not without a token
+        let tokenless = ExitOptions {
+            admin_token: None,
+            ..options.clone()
+        };
+        assert!(ExitService::new(target.clone(), tokenless).is_err());
+        let exit = exit_node_with(target, options).await;
+        let mut stream = entry::connect(exit.clone()).await.unwrap();
+        target_listen.accept().await.unwrap();
+
+        let client = reqwest::Client::new();
+        let sessions = exit.url.join("admin/sessions").unwrap();
+        for token in [None, Some("wrong")] {
+            let mut req = client.get(sessions.clone());
+            if let Some(token) = token {
+                req = req.bearer_auth(token);
+            }
+            let resp = req.send().await.unwrap();
+            assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+        }
+        let resp = client.get(sessions).bearer_auth("secret").send().await;
+        let list: Vec<admin::SessionInfo> =
+            serde_json::from_slice(&resp.unwrap().bytes().await.unwrap()).unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].id, stream.session());
+
+        // as the subcommand does it
+        let url = exit.url.as_str();
+        let session = stream.session().to_string();
+        for args in [
+            &["sessions"][..],
+            &["stats", "--json"],
+            &["kill", &session],
+            &["drain"],
+        ] {
+            let args = [&["admin"], args, &["--url", url, "--token", "secret"]].concat();
+            admin::main(Cli::parse_from(args).command).await;
+        }
+        assert_eq!(stream.read(&mut [0; 4]).await.unwrap(), 0);
+        let stats = exit.url.join("admin/stats").unwrap();
+        let resp = client.get(stats).bearer_auth("secret").send().await;
+        let stats: admin::StatsInfo =
+            serde_json::from_slice(&resp.unwrap().bytes().await.unwrap()).unwrap();
+        assert_eq!(stats.sessions, 0);
+        assert_eq!(stats.closed_by[&crate::close::CloseReason::Killed], 1);
+        assert!(stats.draining);
+    });
+}
+
+#[test]
+fn health() {
+    RT.block_on(async {
+        let target_listen = tokio::net::TcpListener::bind(localhost().await)
+            .await
+            .unwrap();
+        let options = ExitOptions {
+            admin: true,
+            admin_token: Some("secret".to_owned()),
+            ..ExitOptions::default()
+        };
+        let target = Endpoint::Tcp(vec![target_listen.local_addr().unwrap()]);
+        let exit = exit_node_with(target, options).await;
+        entry::check_exit(&exit).await.unwrap();
+
+        let client = reqwest::Client::new();
+        let get = |path| {
+            let req = client.get(exit.url.join(path).unwrap()).send();
+            async move {
+                let resp = req.await.unwrap();
+                (resp.status(), resp.text().await.unwrap())
+            }
+        };
+        assert_eq!(get("healthz").await, (reqwest::StatusCode::OK, "ok".into()));
+        assert_eq!(
+            get("readyz").await,
+            (reqwest::StatusCode::OK, "ready".into())
+        );
+
+        let drain = exit.url.join("admin/drain").unwrap();
+        let resp = client.post(drain).bearer_auth("secret").send().await;
+        assert_eq!(resp.unwrap().status(), reqwest::StatusCode::OK);
+        let unavailable = reqwest::StatusCode::SERVICE_UNAVAILABLE;
+        assert_eq!(get("readyz").await, (unavailable, "draining".into()));
+        // still alive, only not taking sessions
+        assert_eq!(get("healthz").await, (reqwest::StatusCode::OK, "ok".into()));
+        entry::check_exit(&exit).await.unwrap();
+
+        // nothing listening there any more
+        let gone = tokio::net::TcpListener::bind(localhost().await)
+            .await
+            .unwrap();
+        let url = format!("http://{}/", gone.local_addr().unwrap());
+        drop(gone);
+        let gone = ExitNode::new(url.parse().unwrap(), &EntryOptions::default()).unwrap();
+        assert!(entry::check_exit(&gone).await.is_err());
+    });
+}
+
+#[test]
+fn tls() {
+    use crate::admin::{self, AdminCommand};
+    use crate::{entry::TlsClientOptions, exit::TlsServerOptions};
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[clap(subcommand)]
+        command: AdminCommand,
+    }
+
+    // a CA with a certificate for localhost and one for clients, both valid for 100 years
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/tls");
+    RT.block_on(async {
+        let target_listen = tokio::net::TcpListener::bind(localhost().await)
+            .await
+            .unwrap();
+        let options = ExitOptions {
+            admin: true,
+            admin_token: Some("secret".to_owned()),
+            tls: TlsServerOptions {
+                tls_cert: Some(dir.join("server.pem")),
+                tls_key: Some(dir.join("server.key")),
+                tls_client_ca: Some(dir.join("ca.pem")),
+                tls_reload_interval: 0,
+            },
+            http2_stream_window: Some(ByteSize(256 * 1024)),
+            http2_connection_window: Some(ByteSize(1024 * 1024)),
+            ..ExitOptions::default()
+        };
+        let bind = Endpoint::Tcp(vec![localhost().await[0]]);
+        let target = Endpoint::Tcp(vec![target_listen.local_addr().unwrap()]);
+        let (exit_addr, server) = exit::main(&bind, target, options);
+        tokio::spawn(server);
+        let url: reqwest::Url = format!("https://localhost:{}/", tcp(exit_addr).port())
+            .parse()
+            .unwrap();
+
+        let mut options = EntryOptions {
+            tls: TlsClientOptions {
+                tls_ca: Some(dir.join("ca.pem")),
+                ..TlsClientOptions::default()
+            },
+            ..EntryOptions::default()
+        };
+        // the handshake fails without a client certificate
+        let anonymous = ExitNode::new(url.clone(), &options).unwrap();
+        assert!(entry::check_exit(&anonymous).await.is_err());
+
+        options.tls.tls_client_cert = Some(dir.join("client.pem"));
+        options.tls.tls_client_key = Some(dir.join("client.key"));
+        // HTTP/2 through ALPN, with the exit's windows
+        for http2 in [false, true] {
+            options.http2 = http2;
+            let exit = Arc::new(ExitNode::new(url.clone(), &options).unwrap());
+            entry::check_exit(&exit).await.unwrap();
+            let mut stream = entry::connect(exit).await.unwrap();
+            let mut target = target_listen.accept().await.unwrap().0;
+            let data = vec![42; 1024 * 1024];
+            let mut buf = vec![0; data.len()];
+            let (write, read) = join!(stream.write_all(&data), target.read_exact(&mut buf));
+            write.unwrap();
+            read.unwrap();
+            assert_eq!(buf, data);
+            target.write_all(b"pong").await.unwrap();
+            stream.read_exact(&mut buf[..4]).await.unwrap();
+            assert_eq!(&buf[..4], b"pong");
+        }
+
+        // the admin subcommand with the same options, it would exit on a failed handshake
+        let tls = [
+            ("ca", "ca.pem"),
+            ("client-cert", "client.pem"),
+            ("client-key", "client.key"),
+        ]
+        .map(|(flag, file)| format!("--tls-{flag}={}", dir.join(file).display()));
+        let args = ["admin", "drain", "--url", url.as_str(), "--token", "secret"];
+        let args = args.into_iter().map(str::to_owned).chain(tls);
+        admin::main(Cli::parse_from(args).command).await;
+    });
+}
+
+#[test]
+fn path_prefix_headers() {
+    use actix_web::dev::Service;
+
+    RT.block_on(async {
+        let target_listen = tokio::net::TcpListener::bind(localhost().await)
+            .await
+            .unwrap();
+        let target = Endpoint::Tcp(vec![target_listen.local_addr().unwrap()]);
+        let options = ExitOptions {
+            path_prefix: "/tunnel/v1/".to_owned(),
+            ..ExitOptions::default()
+        };
+        let service = ExitService::new(target, options).unwrap();
+        // path, X-Api-Key, Authorization of every request, as a reverse proxy would check them
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = seen.clone();
+        let server = HttpServer::new(move || {
+            let log = log.clone();
+            App::new()
+                .wrap_fn(move |req, srv| {
+                    let header = |x| req.headers().get(x).map(|x| x.to_str().unwrap().to_owned());
+                    let entry = (
+                        req.path().to_owned(),
+                        header("x-api-key"),
+                        header("authorization"),
+                    );
+                    log.lock().unwrap().push(entry);
+                    srv.call(req)
+                })
+                .configure(|cfg| service.configure(cfg))
+        })
+        .bind(localhost().await)
+        .unwrap();
+        let url = format!("http://{}/tunnel/v1", server.addrs()[0]);
+        tokio::spawn(server.run());
+
+        let options = EntryOptions {
+            headers: vec!["X-Api-Key: secret".parse().unwrap()],
+            basic_auth: Some("user:pass".to_owned()),
+            ..EntryOptions::default()
+        };
+        let exit = Arc::new(ExitNode::new(url.parse().unwrap(), &options).unwrap());
+        let mut stream = entry::connect(exit).await.unwrap();
+        let mut target = target_listen.accept().await.unwrap().0;
+        stream.write_all(b"ping").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut request = Vec::new();
+        target.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"ping");
+        target.write_all(b"pong").await.unwrap();
+        drop(target);
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"pong");
+
+        let seen = seen.lock().unwrap();
+        // open, upload, download
+        assert!(seen.len() >= 3, "{seen:?}");
+        for (path, key, auth) in seen.iter() {
+            assert!(path.starts_with("/tunnel/v1/"), "{path}");
+            assert_eq!(key.as_deref(), Some("secret"));
+            // user:pass
+            assert_eq!(auth.as_deref(), Some("Basic dXNlcjpwYXNz"));
+        }
+    });
+}
+
+/// A website answering with the path it was asked for, logging it with the header names.
+async fn decoy_site() -> (reqwest::Url, Arc<std::sync::Mutex<Vec<String>>>) {
+    let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let log = seen.clone();
+    let site = HttpServer::new(move || {
+        let log = log.clone();
+        App::new().default_service(web::to(move |req: actix_web::HttpRequest| {
+            let names = req.headers().keys().join(",");
+            log.lock().unwrap().push(format!("{} {names}", req.uri()));
+            let page = format!("site {}", req.uri());
+            async move { page }
+        }))
+    })
+    .bind(localhost().await)
+    .unwrap();
+    let site_url = format!("http://{}/www", site.addrs()[0]).parse().unwrap();
+    tokio::spawn(site.run());
+    (site_url, seen)
+}
+
+#[test]
+fn decoy() {
+    use crate::decoy::{self, DecoyOptions};
+
+    RT.block_on(async {
+        let (site_url, seen) = decoy_site().await;
+
+        let target_listen = tokio::net::TcpListener::bind(localhost().await)
+            .await
+            .unwrap();
+        let target = Endpoint::Tcp(vec![target_listen.local_addr().unwrap()]);
+        let options = ExitOptions {
+            tunnel_token: Some("secret".to_owned()),
+            admin: true,
+            admin_token: Some("admin".to_owned()),
+            decoy: DecoyOptions {
+                decoy_url: Some(site_url),
+                decoy_strip_header: vec!["x-api-key".parse().unwrap()],
+                ..DecoyOptions::default()
+            },
+            ..ExitOptions::default()
+        };
+        let service = ExitService::new(target, options).unwrap();
+        let server = HttpServer::new(move || {
+            App::new()
+                .configure(|cfg| service.configure(cfg))
+                .default_service(web::to(decoy::respond))
+        })
+        .bind(localhost().await)
+        .unwrap();
+        let exit_addr = server.addrs()[0];
+        tokio::spawn(server.run());
+
+        // tunnel paths without the token, wrong methods and queries included
+        let client = reqwest::Client::new();
+        let url = |path: &str| format!("http://{exit_addr}{path}");
+        let uid = Uuid::new_v4();
+        for (method, path) in [
+            (reqwest::Method::GET, "/open".to_owned()),
+            (reqwest::Method::POST, "/open?wait=x".to_owned()),
+            (reqwest::Method::PUT, "/open".to_owned()),
+            (reqwest::Method::GET, format!("/upload/{uid}")),
+            (reqwest::Method::POST, format!("/download/{uid}")),
+            (reqwest::Method::GET, format!("/close/{uid}")),
+        ] {
+            let resp = client.request(method, url(&path)).send().await.unwrap();
+            assert_eq!(resp.status(), reqwest::StatusCode::OK, "{path}");
+            assert_eq!(resp.text().await.unwrap(), format!("site /www{path}"));
+        }
+        // load balancers can not send the token
+        for (path, body) in [("/healthz", "ok"), ("/readyz", "ready")] {
+            let resp = client.get(url(path)).send().await.unwrap();
+            assert_eq!(resp.text().await.unwrap(), body);
+        }
+        // the probe of readyz
+        drop(target_listen.accept().await.unwrap());
+        // with a token, but no such session, a body not in its encoding or no such command,
+        // kept from the website
+        let tunnel = |req: reqwest::RequestBuilder| req.header("x-tunnel-token", "secret");
+        for req in [
+            tunnel(client.get(url(&format!("/close/{uid}")))),
+            tunnel(client.post(url("/open?encoding=hex")).body("zz\n")),
+            client.get(url("/admin/nope")).bearer_auth("admin"),
+        ] {
+            let resp = req.send().await.unwrap();
+            assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+        }
+        // credentials are not passed on
+        let req = client.get(url("/page")).header("cookie", "a=b");
+        let req = req.basic_auth("user", Some("password"));
+        let req = req.header("x-tunnel-token", "wrong");
+        let resp = req.header("x-api-key", "key").send().await.unwrap();
+        assert_eq!(resp.text().await.unwrap(), "site /www/page");
+
+        // paths which would leave the website, sent as they are
+        for path in [
+            "//evil.example/x",
+            "/http://169.254.169.254/",
+            "/../x",
+            "/%2e%2e/x",
+        ] {
+            let mut conn = TcpStream::connect(exit_addr).await.unwrap();
+            let head = format!("GET {path} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n");
+            conn.write_all(head.as_bytes()).await.unwrap();
+            let mut resp = String::new();
+            conn.read_to_string(&mut resp).await.unwrap();
+            assert!(
+                resp.contains("site /www/") || resp.starts_with("HTTP/1.1 404"),
+                "{resp}"
+            );
+        }
+        for x in seen.lock().unwrap().iter() {
+            assert!(x.starts_with("/www/"), "{x}");
+            for secret in ["x-tunnel-token", "x-api-key", "cookie", "authorization"] {
+                assert!(!x.contains(secret), "{x}");
+            }
+        }
+
+        // an entry with the token gets through
+        let options = EntryOptions {
+            headers: vec!["X-Tunnel-Token: secret".parse().unwrap()],
+            ..EntryOptions::default()
+        };
+        let url = format!("http://{exit_addr}/").parse().unwrap();
+        let exit = Arc::new(ExitNode::new(url, &options).unwrap());
+        entry::check_exit(&exit).await.unwrap();
+        let mut stream = entry::connect(exit).await.unwrap();
+        let mut target = target_listen.accept().await.unwrap().0;
+        target.write_all(b"banner").await.unwrap();
+        let mut buf = [0; 6];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"banner");
+    });
+}
+
+#[test]
+fn connect() {
+    RT.block_on(async {
+        let target_listen = tokio::net::TcpListener::bind(localhost().await)
+            .await
+            .unwrap();
+        let exit = exit_node(Endpoint::Tcp(vec![target_listen.local_addr().unwrap()])).await;
+        let mut stream = entry::connect(exit).await.unwrap();
+        let mut target = target_listen.accept().await.unwrap().0;
+
+        let mut buf = [0; 4];
+        stream.write_all(b"ping").await.unwrap();
+        target.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        target.write_all(b"pong").await.unwrap();
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+
+        // closes the session
+        drop(stream);
+        assert_eq!(target.read(&mut buf).await.unwrap(), 0);
+    });
+}
+
+#[cfg(unix)]
+#[test]
+fn unix() {
+    use tokio::net::{UnixListener, UnixStream};
+
+    let dir = std::env::temp_dir().join(format!("tcp-over-http-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    RT.block_on(async {
+        // a Unix socket target, behind an entry listening on one
+        let target_path = dir.join("target.sock");
+        let target_listen = UnixListener::bind(&target_path).unwrap();
+        let exit = exit_node(Endpoint::Unix(target_path.clone())).await;
+        let bind = Endpoint::Unix(dir.join("entry.sock"));
+        let (_, f_entry) = entry::main(&bind, exit.url.clone(), EntryOptions::default()).await;
+        tokio::spawn(f_entry);
+
+        let mut client = UnixStream::connect(dir.join("entry.sock")).await.unwrap();
+        let mut target = target_listen.accept().await.unwrap().0;
+        let mut buf = [0; 4];
+        client.write_all(b"ping").await.unwrap();
+        target.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        target.write_all(b"pong").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+
+        // a socket still in use is kept, one of a stopped listener replaced
+        crate::remove_stale_socket(&target_path);
+        assert!(target_path.exists());
+        drop(target_listen);
+        crate::remove_stale_socket(&target_path);
+        assert!(!target_path.exists());
+    });
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn command() {
+    RT.block_on(async {
+        let exit = exit_node(Endpoint::Command("echo started; cat; echo done".to_owned())).await;
+        let mut stream = entry::connect(exit).await.unwrap();
+        stream.write_all(b"ping\n").await.unwrap();
+        let mut buf = [0; 13];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"started\nping\n");
+
+        // closes stdin
+        stream.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"done\n");
+    });
+}
+
+#[test]
+fn half_close() {
+    RT.block_on(async {
+        let target_listen = tokio::net::TcpListener::bind(localhost().await)
+            .await
+            .unwrap();
+        let exit = exit_node(Endpoint::Tcp(vec![target_listen.local_addr().unwrap()])).await;
+        let mut stream = entry::connect(exit).await.unwrap();
+        let mut target = target_listen.accept().await.unwrap().0;
+
+        stream.write_all(b"request").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut request = Vec::new();
+        target.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+
+        // the response still gets through
+        target.write_all(b"response").await.unwrap();
+        target.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response");
+    });
+}
+
+#[test]
+fn half_close_target_first() {
+    RT.block_on(async {
+        let target_listen = tokio::net::TcpListener::bind(localhost().await)
+            .await
+            .unwrap();
+        let exit = exit_node(Endpoint::Tcp(vec![target_listen.local_addr().unwrap()])).await;
+        let mut stream = entry::connect(exit).await.unwrap();
+        let mut target = target_listen.accept().await.unwrap().0;
+
+        target.write_all(b"banner").await.unwrap();
+        target.shutdown().await.unwrap();
+        let mut banner = Vec::new();
+        stream.read_to_end(&mut banner).await.unwrap();
+        assert_eq!(banner, b"banner");
+
+        // the client still gets through
+        for _ in 0..3 {
+            stream.write_all(b"more").await.unwrap();
+            sleep(Duration::from_millis(50)).await;
+        }
+        stream.shutdown().await.unwrap();
+        let mut request = Vec::new();
+        target.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"moremoremore");
+    });
+}
+
+#[test]
+fn reset() {
+    RT.block_on(async {
+        let target_listen = tokio::net::TcpListener::bind(localhost().await)
+            .await
+            .unwrap();
+        let exit = exit_node(Endpoint::Tcp(vec![target_listen.local_addr().unwrap()])).await;
+        let stream = entry::connect(exit.clone()).await.unwrap();
+        let mut target = target_listen.accept().await.unwrap().0;
+
+        // as the entry does when its client aborts
+        let url = format!("{}close/{}", exit.url, stream.session());
+        let resp = reqwest::Client::new()
+            .get(url)
+            .query(&[("reason", "client_reset")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.text().await.unwrap(), "client_reset");
+        let err = target.read(&mut [0; 4]).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+    });
+}
+
+#[test]
+fn exit_close() {
+    RT.block_on(async {
+        let target_listen = tokio::net::TcpListener::bind(localhost().await)
+            .await
+            .unwrap();
+        let exit = exit_node(Endpoint::Tcp(vec![target_listen.local_addr().unwrap()])).await;
+        let stream = entry::connect(exit.clone()).await.unwrap();
+        let target = target_listen.accept().await.unwrap().0;
+
+        let url = format!("{}wait/{}", exit.url, stream.session());
+        let wait = tokio::spawn(reqwest::get(url));
+        // otherwise the entry may take the reason before the wait asks for it
+        sleep(Duration::from_millis(100)).await;
+        target.set_linger(Some(Duration::ZERO)).unwrap();
+        drop(target);
+        let resp = wait.await.unwrap().unwrap();
+        assert_eq!(resp.text().await.unwrap(), "target_reset");
+
+        // through a listener, with the upload still streaming
+        let bind = Endpoint::Tcp(vec!["127.0.0.1:0".parse().unwrap()]);
+        let (entry_addr, f_entry) =
+            entry::main(&bind, exit.url.clone(), EntryOptions::default()).await;
+        tokio::spawn(f_entry);
+        let mut client = TcpStream::connect(tcp(entry_addr)).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let target = target_listen.accept().await.unwrap().0;
+        target.set_linger(Some(Duration::ZERO)).unwrap();
+        drop(target);
+        // the entry mirrors the reset, instead of just closing
+        let read = client.read(&mut [0; 4]).await;
+        assert_eq!(
+            read.unwrap_err().kind(),
+            std::io::ErrorKind::ConnectionReset
+        );
+
+        // a garbled upload is the client's fault, not the target's
+        let client = reqwest::Client::new();
+        let open = exit.url.join("open?encoding=hex").unwrap();
+        let resp = client.get(open).send().await.unwrap();
+        let body = resp.bytes().await.unwrap();
+        let uid = BodyEncoding::Hex.decode(body).unwrap();
+        let uid = Uuid::from_slice(&uid).unwrap();
+        let mut target = target_listen.accept().await.unwrap().0;
+        let upload = exit.url.join(&format!("upload/{uid}")).unwrap();
+        let resp = client.post(upload).body("zz\n").send().await.unwrap();
+        assert_eq!(resp.text().await.unwrap(), "client disconnect");
+        let wait = format!("{}wait/{uid}", exit.url);
+        let resp = reqwest::get(wait).await.unwrap();
+        assert_eq!(resp.text().await.unwrap(), "client_reset");
+        let err = target.read(&mut [0; 4]).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+    });
+}
+
+#[test]
+fn early_data() {
+    RT.block_on(async {
+        let target_listen = tokio::net::TcpListener::bind(localhost().await)
+            .await
+            .unwrap();
+        let exit = exit_node(Endpoint::Tcp(vec![target_listen.local_addr().unwrap()])).await;
+        let options = EntryOptions {
+            early_data: Some(1000),
+            ..EntryOptions::default()
+        };
+        let exit = Arc::new(ExitNode::new(exit.url.clone(), &options).unwrap());
+        let (mut client, socket) = tokio::io::duplex(1024);
+        tokio::spawn(entry::process_socket(exit.clone(), socket, None, None));
+
+        // answered within the open request
+        client.write_all(b"request").await.unwrap();
+        let mut target = target_listen.accept().await.unwrap().0;
+        let mut buf = [0; 7];
+        target.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"request");
+        target.write_all(b"reply").await.unwrap();
+        client.read_exact(&mut buf[..5]).await.unwrap();
+        assert_eq!(&buf[..5], b"reply");
+
+        // then as usual
+        client.write_all(b"more").await.unwrap();
+        target.read_exact(&mut buf[..4]).await.unwrap();
+        assert_eq!(&buf[..4], b"more");
+        target.write_all(b"bye").await.unwrap();
+        client.read_exact(&mut buf[..3]).await.unwrap();
+        assert_eq!(&buf[..3], b"bye");
+
+        // server-first, the client is not waited for
+        let (mut client, socket) = tokio::io::duplex(1024);
+        let start = std::time::Instant::now();
+        tokio::spawn(entry::process_socket(exit, socket, None, None));
+        let mut target = target_listen.accept().await.unwrap().0;
+        target.write_all(b"220 hi").await.unwrap();
+        client.read_exact(&mut buf[..6]).await.unwrap();
+        assert_eq!(&buf[..6], b"220 hi");
+        assert!(start.elapsed() < Duration::from_millis(500));
+    });
+}
+
+#[test]
+fn early_data_throttled() {
+    RT.block_on(async {
+        let target_listen = tokio::net::TcpListener::bind(localhost().await)
+            .await
+            .unwrap();
+        let options = ExitOptions {
+            session_rate: Some(ByteSize(4 * 1024)),
+            ..ExitOptions::default()
+        };
+        let target = Endpoint::Tcp(vec![target_listen.local_addr().unwrap()]);
+        let exit = exit_node_with(target, options).await;
+        let options = EntryOptions {
+            early_data: Some(100),
+            ..EntryOptions::default()
+        };
+        let exit = Arc::new(ExitNode::new(exit.url.clone(), &options).unwrap());
+        let (mut client, socket) = tokio::io::duplex(16 * 1024);
+
+        // one second of burst, the rest is paid by what follows
+        let early = vec![42; 8 * 1024];
+        client.write_all(&early).await.unwrap();
+        let start = std::time::Instant::now();
+        tokio::spawn(entry::process_socket(exit, socket, None, None));
+        let mut target = target_listen.accept().await.unwrap().0;
+        let mut received = vec![0; early.len()];
+        target.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, early);
+        client.write_all(b"more").await.unwrap();
+        target.read_exact(&mut received[..4]).await.unwrap();
+        assert_eq!(&received[..4], b"more");
+        assert!(start.elapsed() >= Duration::from_millis(800));
+    });
+}
+
+#[test]
+fn proxy_protocol() {
+    use crate::proxy_protocol::{header, ProxyProtocol};
+
+    RT.block_on(async {
+        for version in [ProxyProtocol::V1, ProxyProtocol::V2] {
+            let target_listen = tokio::net::TcpListener::bind(localhost().await)
+                .await
+                .unwrap();
+            let options = ExitOptions {
+                proxy_protocol: Some(version),
+                trust_entry_address: true,
+                ..ExitOptions::default()
+            };
+            let target = Endpoint::Tcp(vec![target_listen.local_addr().unwrap()]);
+            let exit = exit_node_with(target, options).await;
+            let bind = Endpoint::Tcp(vec!["127.0.0.1:0".parse().unwrap()]);
+            let (entry_addr, f_entry) =
+                entry::main(&bind, exit.url.clone(), EntryOptions::default()).await;
+            tokio::spawn(f_entry);
+            let entry_addr = tcp(entry_addr);
+
+            let mut client = TcpStream::connect(entry_addr).await.unwrap();
+            client.write_all(b"hello").await.unwrap();
+            let mut target = target_listen.accept().await.unwrap().0;
+
+            // the client as seen by the entry, not the exit's own connection
+            let expected = header(version, Some((client.local_addr().unwrap(), entry_addr)));
+            let mut received = vec![0; expected.len() + 5];
+            target.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, [&expected[..], b"hello"].concat());
+        }
+
+        // untrusted, the exit's own view
+        let target_listen = tokio::net::TcpListener::bind(localhost().await)
+            .await
+            .unwrap();
+        let options = ExitOptions {
+            proxy_protocol: Some(ProxyProtocol::V1),
+            ..ExitOptions::default()
+        };
+        let target = Endpoint::Tcp(vec![target_listen.local_addr().unwrap()]);
+        let exit = exit_node_with(target, options).await;
+        let open = exit.url.join("open?src=6.6.6.6:1&dst=6.6.6.6:2").unwrap();
+        reqwest::get(open).await.unwrap();
+        let mut target = target_listen.accept().await.unwrap().0;
+        let mut line = Vec::new();
+        while !line.ends_with(b"\n") {
+            line.push(target.read_u8().await.unwrap());
+        }
+        let line = String::from_utf8(line).unwrap();
+        let exit_addr = exit.url.socket_addrs(|| None).unwrap()[0];
+        assert!(
+            line.starts_with("PROXY TCP4 127.0.0.1 127.0.0.1 "),
+            "{line}"
+        );
+        assert!(
+            line.ends_with(&format!(" {}\r\n", exit_addr.port())),
+            "{line}"
+        );
+
+        // the readiness probe tells the target it is no client's
+        let readyz = exit.url.join("readyz").unwrap();
+        reqwest::get(readyz).await.unwrap();
+        let mut probe = target_listen.accept().await.unwrap().0;
+        let mut received = Vec::new();
+        probe.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"PROXY UNKNOWN\r\n");
+    });
+}
+
+#[test]
+fn throttle() {
+    RT.block_on(async {
+        let target_listen = tokio::net::TcpListener::bind(localhost().await)
+            .await
+            .unwrap();
+        let options = ExitOptions {
+            session_rate: Some(ByteSize(64 * 1024)),
+            open_rate: Some(1),
+            ..ExitOptions::default()
+        };
+        let target = Endpoint::Tcp(vec![target_listen.local_addr().unwrap()]);
+        let exit = exit_node_with(target, options).await;
+        let mut stream = entry::connect(exit.clone()).await.unwrap();
+        let mut target = target_listen.accept().await.unwrap().0;
+
+        // refused with a Retry-After, then let through
+        let start = std::time::Instant::now();
+        let second = entry::connect(exit).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        drop(second);
+
+        // one second of burst, one of refill
+        let start = std::time::Instant::now();
+        let data = vec![42; 128 * 1024];
+        let write = async {
+            target.write_all(&data).await.unwrap();
+            target.shutdown().await.unwrap();
+        };
+        let mut received = Vec::new();
+        let ((), read) = join!(write, stream.read_to_end(&mut received));
+        read.unwrap();
+        assert_eq!(received, data);
+        assert!(start.elapsed() >= Duration::from_millis(800));
+
+        // counted per --client-header, as the sessions per client are
+        let options = ExitOptions {
+            open_rate: Some(1),
+            client_header: Some("x-client".parse().unwrap()),
+            ..ExitOptions::default()
+        };
+        let target = Endpoint::Tcp(vec![target_listen.local_addr().unwrap()]);
+        let exit = exit_node_with(target, options).await;
+        let open = exit.url.join("open").unwrap();
+        let client = reqwest::Client::new();
+        for (key, status) in [
+            ("a", reqwest::StatusCode::OK),
+            ("a", reqwest::StatusCode::TOO_MANY_REQUESTS),
+            ("b", reqwest::StatusCode::OK),
+        ] {
+            let req = client.get(open.clone()).header("x-client", key);
+            assert_eq!(req.send().await.unwrap().status(), status, "{key}");
+        }
+    });
+}
+
+#[test]
+fn pool() {
+    RT.block_on(async {
+        let target_listen = tokio::net::TcpListener::bind(localhost().await)
+            .await
+            .unwrap();
+        let exit = exit_node(Endpoint::Tcp(vec![target_listen.local_addr().unwrap()])).await;
+        let options = EntryOptions {
+            pool: 1,
+            ..EntryOptions::default()
+        };
+        let exit = Arc::new(ExitNode::new(exit.url.clone(), &options).unwrap());
+        tokio::spawn(crate::pool::fill(exit.clone()));
+
+        // connected before there is a client
+        let mut target = target_listen.accept().await.unwrap().0;
+        // pooled once `/open` answered, not yet when the target is connected
+        sleep(Duration::from_millis(100)).await;
+        let (mut client, socket) = tokio::io::duplex(1024);
+        tokio::spawn(entry::process_socket(exit, socket, None, None));
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        target.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        target.write_all(b"world").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+
+        // and replaced
+        target_listen.accept().await.unwrap();
+    });
+}
+
+#[test]
+fn http2() {
+    RT.block_on(async {
+        let target_listen = tokio::net::TcpListener::bind(localhost().await)
+            .await
+            .unwrap();
+        let target = Endpoint::Tcp(vec![target_listen.local_addr().unwrap()]);
+        let service = ExitService::new(target, ExitOptions::default()).unwrap();
+        let windows = exit::Http2Windows::default();
+        let (exit_addr, server) = exit::serve_h2c(localhost().await, windows, move || {
+            App::new().configure(|cfg| service.configure(cfg))
+        });
+        tokio::spawn(server);
+        let url: reqwest::Url = format!("http://{}/", tcp(exit_addr)).parse().unwrap();
+
+        // both on the same listener
+        for http2 in [false, true] {
+            let options = EntryOptions {
+                http2,
+                ..EntryOptions::default()
+            };
+            let exit = Arc::new(ExitNode::new(url.clone(), &options).unwrap());
+            let mut stream = entry::connect(exit).await.unwrap();
+            let mut target = target_listen.accept().await.unwrap().0;
+            let mut buf = [0; 4];
+            stream.write_all(b"ping").await.unwrap();
+            target.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            target.write_all(b"pong").await.unwrap();
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"pong");
+        }
+    });
+}
+
+#[test]
+fn stream() {
+    RT.block_on(async {
+        let target_listen = tokio::net::TcpListener::bind(localhost().await)
+            .await
+            .unwrap();
+        let target = Endpoint::Tcp(vec![target_listen.local_addr().unwrap()]);
+        let service = ExitService::new(target, ExitOptions::default()).unwrap();
+        let windows = exit::Http2Windows::default();
+        let (exit_addr, server) = exit::serve_h2c(localhost().await, windows, move || {
+            App::new().configure(|cfg| service.configure(cfg))
+        });
+        tokio::spawn(server);
+        let url: reqwest::Url = format!("http://{}/", tcp(exit_addr)).parse().unwrap();
+
+        for http2 in [false, true] {
+            let options = EntryOptions {
+                stream: true,
+                http2,
+                ..EntryOptions::default()
+            };
+            let exit = Arc::new(ExitNode::new(url.clone(), &options).unwrap());
+            let mut stream = entry::connect(exit).await.unwrap();
+            let mut target = target_listen.accept().await.unwrap().0;
+
+            // answered while the request body is still open
+            let mut buf = [0; 4];
+            for _ in 0..3 {
+                stream.write_all(b"ping").await.unwrap();
+                target.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"ping");
+                target.write_all(b"pong").await.unwrap();
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"pong");
+            }
+
+            // over HTTP/2 the target's FIN arrives while the client still sends,
+            // over HTTP/1.1 it waits for the client's
+            target.write_all(b"bye").await.unwrap();
+            target.shutdown().await.unwrap();
+            stream.read_exact(&mut buf[..3]).await.unwrap();
+            assert_eq!(&buf[..3], b"bye");
+            if http2 {
+                assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+            }
+            stream.write_all(b"last").await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut rest = Vec::new();
+            target.read_to_end(&mut rest).await.unwrap();
+            assert_eq!(rest, b"last");
+            assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+        }
+    });
+}
+
+#[test]
+fn body_encoding() {
+    RT.block_on(async {
+        let target_listen = tokio::net::TcpListener::bind(localhost().await)
+            .await
+            .unwrap();
+        let exit = exit_node(Endpoint::Tcp(vec![target_listen.local_addr().unwrap()])).await;
+
+        let resp = reqwest::get(exit.url.join("open?encoding=hex").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[reqwest::header::CONTENT_TYPE], "text/plain");
+        let body = resp.text().await.unwrap();
+        assert_eq!(body.len(), 33);
+        assert!(body.trim_end().bytes().all(|x| x.is_ascii_hexdigit()));
+        target_listen.accept().await.unwrap();
+
+        for (body_encoding, stream) in
+            [(BodyEncoding::Base64Json, false), (BodyEncoding::Hex, true)]
+        {
+            let options = EntryOptions {
+                body_encoding,
+                stream,
+                early_data: Some(100),
+                ..EntryOptions::default()
+            };
+            let exit = Arc::new(ExitNode::new(exit.url.clone(), &options).unwrap());
+            let (mut client, socket) = tokio::io::duplex(1024);
+            tokio::spawn(entry::process_socket(exit, socket, None, None));
+            client.write_all(b"\x00\xffping").await.unwrap();
+            let mut target = target_listen.accept().await.unwrap().0;
+            let mut buf = [0; 6];
+            target.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"\x00\xffping");
+
+            // in both directions, and after the open request
+            target.write_all(b"\n{pong").await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"\n{pong");
+            client.write_all(b"\r\n\"\"").await.unwrap();
+            target.read_exact(&mut buf[..4]).await.unwrap();
+            assert_eq!(&buf[..4], b"\r\n\"\"");
+        }
+    });
+}
+
+#[test]
+fn parts() {
+    RT.block_on(async {
+        let target_listen = tokio::net::TcpListener::bind(localhost().await)
+            .await
+            .unwrap();
+        let exit = exit_node(Endpoint::Tcp(vec![target_listen.local_addr().unwrap()])).await;
+
+        for body_encoding in [BodyEncoding::Binary, BodyEncoding::Hex] {
+            let options = EntryOptions {
+                body_encoding,
+                upload_part_size: Some(ByteSize(4)),
+                download_part_size: Some(ByteSize(4)),
+                download_part_secs: Some(1),
+                ..EntryOptions::default()
+            };
+            let exit = Arc::new(ExitNode::new(exit.url.clone(), &options).unwrap());
+            let mut stream = entry::connect(exit).await.unwrap();
+            let mut target = target_listen.accept().await.unwrap().0;
+
+            let mut buf = [0; 10];
+            stream.write_all(b"0123456789").await.unwrap();
+            target.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"0123456789");
+            for data in [&b"abcdefghij"[..], b"k"] {
+                target.write_all(data).await.unwrap();
+                stream.read_exact(&mut buf[..data.len()]).await.unwrap();
+                assert_eq!(&buf[..data.len()], data);
+            }
+
+            // past the end of a part
+            target.shutdown().await.unwrap();
+            assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+            stream.write_all(b"last").await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut rest = Vec::new();
+            target.read_to_end(&mut rest).await.unwrap();
+            assert_eq!(rest, b"last");
+        }
+
+        // copied in order, whichever arrives first
+        let client = reqwest::Client::new();
+        let resp = client.get(exit.url.join("open").unwrap()).send().await;
+        let uid = Uuid::from_slice(&resp.unwrap().bytes().await.unwrap()).unwrap();
+        let mut target = target_listen.accept().await.unwrap().0;
+        let upload = exit.url.join(&format!("upload/{uid}")).unwrap();
+        let post = |seq: u64, fin: bool, body: &'static str| {
+            let req = client.post(upload.clone()).query(&[("seq", seq)]);
+            req.query(&[("fin", fin)]).body(body).send()
+        };
+        let second = tokio::spawn(post(1, true, "world"));
+        sleep(Duration::from_millis(100)).await;
+        let first = post(0, false, "hello").await.unwrap();
+        assert_eq!(first.text().await.unwrap(), "received");
+        assert_eq!(
+            second.await.unwrap().unwrap().text().await.unwrap(),
+            "finished"
+        );
+        let mut written = Vec::new();
+        target.read_to_end(&mut written).await.unwrap();
+        assert_eq!(written, b"helloworld");
+        let again = post(0, false, "hello").await.unwrap();
+        assert_eq!(again.status(), reqwest::StatusCode::CONFLICT);
+
+        // a retry while the part is still on its way is not copied as well
+        let resp = client.get(exit.url.join("open").unwrap()).send().await;
+        let uid = Uuid::from_slice(&resp.unwrap().bytes().await.unwrap()).unwrap();
+        let mut target = target_listen.accept().await.unwrap().0;
+        let upload = exit.url.join(&format!("upload/{uid}")).unwrap();
+        let post = |seq: u64, fin: bool, body: reqwest::Body| {
+            let req = client.post(upload.clone()).query(&[("seq", seq)]);
+            req.query(&[("fin", fin)]).body(body).send()
+        };
+        let (tx, rx) = tokio::sync::mpsc::channel::<std::io::Result<&'static [u8]>>(1);
+        let body = reqwest::Body::wrap_stream(tokio_stream::wrappers::ReceiverStream::new(rx));
+        let first = tokio::spawn(post(0, false, body));
+        tx.send(Ok(b"hel")).await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+        let retry = tokio::spawn(post(0, false, "dup".into()));
+        sleep(Duration::from_millis(100)).await;
+        tx.send(Ok(b"lo")).await.unwrap();
+        drop(tx);
+        let first = first.await.unwrap().unwrap();
+        assert_eq!(first.text().await.unwrap(), "received");
+        let retry = retry.await.unwrap().unwrap();
+        assert_eq!(retry.status(), reqwest::StatusCode::CONFLICT);
+
+        // the download ended, asking again does not end the upload
+        target.shutdown().await.unwrap();
+        let download = exit.url.join(&format!("download/{uid}?size=100")).unwrap();
+        let resp = client.get(download.clone()).send().await.unwrap();
+        assert!(resp.bytes().await.unwrap().is_empty());
+        for _ in 0..2 {
+            let resp = client.get(download.clone()).send().await.unwrap();
+            assert_eq!(resp.status(), reqwest::StatusCode::GONE);
+        }
+        let last = post(1, true, "!".into()).await.unwrap();
+        assert_eq!(last.text().await.unwrap(), "finished");
+        let mut written = Vec::new();
+        target.read_to_end(&mut written).await.unwrap();
+        assert_eq!(written, b"hello!");
+    });
+}
+
+#[test]
+fn parts_encoded() {
+    use actix_web::{dev::Service, HttpMessage};
+    use futures::StreamExt;
+
+    RT.block_on(async {
+        let target_listen = tokio::net::TcpListener::bind(localhost().await)
+            .await
+            .unwrap();
+        let target = Endpoint::Tcp(vec![target_listen.local_addr().unwrap()]);
+        let service = ExitService::new(target, ExitOptions::default()).unwrap();
+        // size of every upload body, as a proxy limiting it would see them
+        let sizes = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = sizes.clone();
+        let server = HttpServer::new(move || {
+            let log = log.clone();
+            App::new()
+                .wrap_fn(move |mut req, srv| {
+                    if req.path().starts_with("/upload/") {
+                        let (log, mut size) = (log.clone(), 0);
+                        let payload = req.take_payload().inspect(move |x| {
+                            size += x.as_ref().map_or(0, bytes::Bytes::len);
+                            log.lock().unwrap().push(size);
+                        });
+                        req.set_payload(actix_web::dev::Payload::Stream {
+                            payload: Box::pin(payload),
+                        });
+                    }
+                    srv.call(req)
+                })
+                .configure(|cfg| service.configure(cfg))
+        })
+        .bind(localhost().await)
+        .unwrap();
+        let url = format!("http://{}", server.addrs()[0]);
+        tokio::spawn(server.run());
+
+        for (body_encoding, size) in [(BodyEncoding::Hex, 9), (BodyEncoding::Base64Json, 20)] {
+            let options = EntryOptions {
+                body_encoding,
+                upload_part_size: Some(ByteSize(size)),
+                ..EntryOptions::default()
+            };
+            let exit = Arc::new(ExitNode::new(url.parse().unwrap(), &options).unwrap());
+            let mut stream = entry::connect(exit).await.unwrap();
+            let mut target = target_listen.accept().await.unwrap().0;
+            stream.write_all(b"0123456789").await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut written = Vec::new();
+            target.read_to_end(&mut written).await.unwrap();
+            assert_eq!(written, b"0123456789");
+            let sizes = std::mem::take(&mut *sizes.lock().unwrap());
+            assert!(!sizes.is_empty());
+            let limit = usize::try_from(size).unwrap();
+            assert!(sizes.iter().all(|&x| x <= limit), "{sizes:?}");
+        }
+
+        // no byte fits
+        let options = EntryOptions {
+            body_encoding: BodyEncoding::Base64,
+            upload_part_size: Some(ByteSize(4)),
+            ..EntryOptions::default()
+        };
+        assert!(ExitNode::new(url.parse().unwrap(), &options).is_err());
+    });
+}
+
+#[test]
+fn upload_answered_early() {
+    RT.block_on(async {
+        let target_listen = tokio::net::TcpListener::bind(localhost().await)
+            .await
+            .unwrap();
+        let exit = exit_node(Endpoint::Tcp(vec![target_listen.local_addr().unwrap()])).await;
+        let client = reqwest::Client::new();
+        let resp = client.get(exit.url.join("open").unwrap()).send().await;
+        let uid = Uuid::from_slice(&resp.unwrap().bytes().await.unwrap()).unwrap();
+        let _target = target_listen.accept().await.unwrap().0;
+
+        // the session ends within the body
+        let exit_addr = exit.url.socket_addrs(|| None).unwrap()[0];
+        let mut conn = TcpStream::connect(exit_addr).await.unwrap();
+        let head = format!("POST /upload/{uid} HTTP/1.1\r\nHost: x\r\nContent-Length: 10\r\n\r\n");
+        conn.write_all(head.as_bytes()).await.unwrap();
+        conn.write_all(b"0123").await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+        let close = exit.url.join(&format!("close/{uid}")).unwrap();
+        client.get(close).send().await.unwrap();
+        conn.write_all(b"456789").await.unwrap();
+
+        // answered, and the connection takes the next request
+        conn.write_all(b"GET /healthz HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        let read = conn.read_to_string(&mut resp);
+        tokio::time::timeout(Duration::from_secs(10), read)
+            .await
+            .unwrap()
+            .unwrap();
+        let answers = resp.matches("HTTP/1.1 200 OK").count();
+        assert_eq!(answers, 2, "{resp}");
+        assert!(resp.contains("cancelled") && resp.ends_with("ok"), "{resp}");
+    });
+}
+
+#[test]
+fn udp() {
+    RT.block_on(async {
+        let target = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let exit = exit_node(Endpoint::Udp(vec![target.local_addr().unwrap()])).await;
+        let bind = Endpoint::Udp(vec!["127.0.0.1:0".parse().unwrap()]);
+        let (entry_addr, f_entry) =
+            entry::main(&bind, exit.url.clone(), EntryOptions::default()).await;
+        tokio::spawn(f_entry);
+        let Endpoint::Udp(entry_addr) = entry_addr else {
+            unreachable!()
+        };
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(entry_addr[0]).await.unwrap();
+        client.send(b"ping").await.unwrap();
+        client.send(b"").await.unwrap();
+        client.send(b"pong").await.unwrap();
+
+        // boundaries are kept
+        let mut buf = [0; 16];
+        let (len, from) = target.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        let (len, _) = target.recv_from(&mut buf).await.unwrap();
+        assert_eq!(len, 0);
+        let (len, _) = target.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"pong");
+        target.send_to(b"a", from).await.unwrap();
+        target.send_to(b"bc", from).await.unwrap();
+        let len = client.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"a");
+        let len = client.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"bc");
     });
 }
 
 #[test]
 fn resolve() {
     RT.block_on(async {
-        let addr = ResolveAddr("localhost:0".to_owned()).resolve().await;
+        let addr = ResolveAddr("unix:/run/x.sock".to_owned()).resolve().await;
+        assert_eq!(addr, Endpoint::Unix("/run/x.sock".into()));
+        let addr = ResolveAddr("cmd:nc localhost 22".to_owned())
+            .resolve()
+            .await;
+        assert_eq!(addr, Endpoint::Command("nc localhost 22".into()));
+        let addr = ResolveAddr("udp:127.0.0.1:53".to_owned()).resolve().await;
+        assert_eq!(addr, Endpoint::Udp(vec!["127.0.0.1:53".parse().unwrap()]));
+        let addr = tcp_all(ResolveAddr("localhost:0".to_owned()).resolve().await);
         let addr = addr.into_iter().map(|x| x.to_string()).collect::<Vec<_>>();
         assert_eq!(addr, ["[::1]:0", "127.0.0.1:0"]);
     });
@@ -214,6 +1496,17 @@ lazy_static::lazy_static! {
     static ref RT: tokio::runtime::Runtime = tokio::runtime::Runtime::new().unwrap();
 }
 
+fn tcp_all(endpoint: Endpoint) -> Vec<SocketAddr> {
+    let Endpoint::Tcp(x) = endpoint else {
+        panic!("{endpoint} is no TCP endpoint");
+    };
+    x
+}
+
+fn tcp(endpoint: Endpoint) -> SocketAddr {
+    tcp_all(endpoint)[0]
+}
+
 async fn localhost() -> &'static [SocketAddr] {
     static ONCE: tokio::sync::OnceCell<Vec<SocketAddr>> = tokio::sync::OnceCell::const_new();
 
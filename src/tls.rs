@@ -0,0 +1,375 @@
+use anyhow::{anyhow, Context};
+use base64::Engine;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::server::{AllowAnyAuthenticatedClient, ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{
+    Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerConfig,
+    ServerName,
+};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone, Debug, Default, clap::Args)]
+#[allow(clippy::struct_field_names)]
+pub struct TlsServerOptions {
+    /// Serve HTTPS with this PEM certificate chain. Requires --tls-key.
+    #[clap(long, value_name = "PATH", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key (PKCS#8, PKCS#1 or SEC1) of --tls-cert.
+    #[clap(long, value_name = "PATH", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Require clients to present a certificate signed by one of these PEM CAs (mTLS).
+    #[clap(long, value_name = "PATH", requires = "tls_cert")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// Seconds between checks whether certificate or key changed on disk.
+    /// Changed files are loaded without restarting. 0 disables reloading.
+    #[clap(long, value_name = "SECS", default_value_t = 60)]
+    pub tls_reload_interval: u64,
+}
+
+pub(crate) fn load_certs(path: &Path) -> anyhow::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path).with_context(|| path.display().to_string())?);
+    let certs = rustls_pemfile::certs(&mut reader).with_context(|| path.display().to_string())?;
+    if certs.is_empty() {
+        return Err(anyhow!("{} contains no certificate", path.display()));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+pub(crate) fn load_key(path: &Path) -> anyhow::Result<PrivateKey> {
+    use rustls_pemfile::Item;
+
+    let mut reader = BufReader::new(File::open(path).with_context(|| path.display().to_string())?);
+    for item in rustls_pemfile::read_all(&mut reader).with_context(|| path.display().to_string())? {
+        if let Item::PKCS8Key(x) | Item::RSAKey(x) | Item::ECKey(x) = item {
+            return Ok(PrivateKey(x));
+        }
+    }
+    Err(anyhow!("{} contains no private key", path.display()))
+}
+
+pub(crate) fn load_roots(path: &Path) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(&cert)
+            .with_context(|| path.display().to_string())?;
+    }
+    Ok(roots)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+/// Serves the certificate currently on disk.
+struct ReloadingCert {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<(Arc<CertifiedKey>, Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl ReloadingCert {
+    fn load(cert_path: &Path, key_path: &Path) -> anyhow::Result<Arc<CertifiedKey>> {
+        let certs = load_certs(cert_path)?;
+        let key = rustls::sign::any_supported_type(&load_key(key_path)?)
+            .map_err(|x| anyhow!("{}: {x}", key_path.display()))?;
+        Ok(Arc::new(CertifiedKey::new(certs, key)))
+    }
+
+    fn new(cert_path: PathBuf, key_path: PathBuf) -> anyhow::Result<Self> {
+        let stamps = (modified(&cert_path), modified(&key_path));
+        let key = Self::load(&cert_path, &key_path)?;
+        Ok(Self {
+            cert_path,
+            key_path,
+            current: RwLock::new((key, stamps.0, stamps.1)),
+        })
+    }
+
+    fn reload_if_changed(&self) {
+        let stamps = (modified(&self.cert_path), modified(&self.key_path));
+        {
+            let current = self.current.read().unwrap();
+            if (current.1, current.2) == stamps {
+                return;
+            }
+        }
+        match Self::load(&self.cert_path, &self.key_path) {
+            Ok(key) => {
+                println!("Reloaded TLS certificate {}", self.cert_path.display());
+                *self.current.write().unwrap() = (key, stamps.0, stamps.1);
+            }
+            // e.g. only one of the files written yet, try again next time
+            Err(x) => eprintln!("Could not reload TLS certificate: {x:#}"),
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().0.clone())
+    }
+}
+
+/// `None` if HTTPS is not configured.
+pub(crate) fn server_config(options: &TlsServerOptions) -> anyhow::Result<Option<ServerConfig>> {
+    let (Some(cert), Some(key)) = (&options.tls_cert, &options.tls_key) else {
+        return Ok(None);
+    };
+    let resolver = Arc::new(ReloadingCert::new(cert.clone(), key.clone())?);
+    if options.tls_reload_interval > 0 {
+        let resolver = Arc::downgrade(&resolver);
+        let interval = Duration::from_secs(options.tls_reload_interval);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            match resolver.upgrade() {
+                Some(x) => x.reload_if_changed(),
+                None => break,
+            }
+        });
+    }
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &options.tls_client_ca {
+        Some(ca) => {
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(load_roots(ca)?))
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Some(builder.with_cert_resolver(resolver)))
+}
+
+#[derive(Clone, Debug, Default, clap::Args)]
+#[allow(clippy::struct_field_names)]
+pub struct TlsClientOptions {
+    /// Also trust these PEM CA certificates for the exit node's certificate.
+    #[clap(long, value_name = "PATH")]
+    pub tls_ca: Option<PathBuf>,
+
+    /// Present this PEM client certificate chain to the exit node (mTLS). Requires --tls-client-key.
+    #[clap(long, value_name = "PATH", requires = "tls_client_key")]
+    pub tls_client_cert: Option<PathBuf>,
+
+    /// PEM private key of --tls-client-cert.
+    #[clap(long, value_name = "PATH", requires = "tls_client_cert")]
+    pub tls_client_key: Option<PathBuf>,
+
+    /// Only accept an exit certificate matching one of these pins:
+    /// `sha256//<base64>` of its public key (as curl's --pinnedpubkey)
+    /// or the hex SHA-256 fingerprint of the certificate.
+    #[clap(long, value_name = "PIN")]
+    pub tls_pin: Vec<Pin>,
+
+    /// Do not verify the exit node's certificate at all. Only for lab setups.
+    #[clap(long)]
+    pub tls_insecure: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Pin {
+    PublicKey([u8; 32]),
+    Certificate([u8; 32]),
+}
+
+impl FromStr for Pin {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digest = |bytes: Vec<u8>| -> Result<[u8; 32], String> {
+            bytes
+                .try_into()
+                .map_err(|_| "not a SHA-256 digest".to_owned())
+        };
+        if let Some(b64) = s.strip_prefix("sha256//") {
+            let bytes = base64::engine::general_purpose::STANDARD
+                .decode(b64)
+                .map_err(|x| x.to_string())?;
+            return Ok(Self::PublicKey(digest(bytes)?));
+        }
+        let hex = s.replace(':', "");
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                hex.get(i..i + 2)
+                    .and_then(|x| u8::from_str_radix(x, 16).ok())
+            })
+            .collect::<Option<Vec<u8>>>()
+            .ok_or("invalid hex")?;
+        Ok(Self::Certificate(digest(bytes)?))
+    }
+}
+
+/// Splits off the first DER element: (whole element, its content, rest).
+fn der_split(der: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let first_len = *der.get(1)?;
+    let (len, header) = if first_len < 0x80 {
+        (usize::from(first_len), 2)
+    } else {
+        let n = usize::from(first_len & 0x7f);
+        let len = der.get(2..2 + n)?.iter().try_fold(0usize, |acc, x| {
+            acc.checked_mul(256)?.checked_add(usize::from(*x))
+        })?;
+        (len, 2 + n)
+    };
+    let element = der.get(..header.checked_add(len)?)?;
+    Some((element, &element[header..], &der[element.len()..]))
+}
+
+/// `SubjectPublicKeyInfo` of an X.509 certificate, as DER.
+fn spki(cert: &[u8]) -> Option<&[u8]> {
+    let (_, cert, _) = der_split(cert)?;
+    let (_, mut tbs, _) = der_split(cert)?;
+    // optional explicit version
+    if tbs.first() == Some(&0xa0) {
+        tbs = der_split(tbs)?.2;
+    }
+    // serial, signature, issuer, validity, subject
+    for _ in 0..5 {
+        tbs = der_split(tbs)?.2;
+    }
+    Some(der_split(tbs)?.0)
+}
+
+struct PinningVerifier {
+    /// `None` accepts any certificate chain.
+    webpki: Option<WebPkiVerifier>,
+    pins: Vec<Pin>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(webpki) = &self.webpki {
+            webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            )?;
+        }
+        if self.pins.is_empty() {
+            return Ok(ServerCertVerified::assertion());
+        }
+        let cert = Pin::Certificate(Sha256::digest(&end_entity.0).into());
+        let key = spki(&end_entity.0).map(|x| Pin::PublicKey(Sha256::digest(x).into()));
+        if self
+            .pins
+            .iter()
+            .any(|x| *x == cert || Some(x) == key.as_ref())
+        {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "certificate does not match any pin".to_owned(),
+            ))
+        }
+    }
+}
+
+/// `None` if the defaults suffice.
+pub(crate) fn client_config(options: &TlsClientOptions) -> anyhow::Result<Option<ClientConfig>> {
+    if options.tls_ca.is_none()
+        && options.tls_client_cert.is_none()
+        && options.tls_pin.is_empty()
+        && !options.tls_insecure
+    {
+        return Ok(None);
+    }
+    let webpki = if options.tls_insecure {
+        eprintln!("Warning: the exit node's TLS certificate is not verified");
+        None
+    } else {
+        let mut roots = RootCertStore::empty();
+        roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|x| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                x.subject,
+                x.spki,
+                x.name_constraints,
+            )
+        }));
+        if let Some(ca) = &options.tls_ca {
+            for cert in load_certs(ca)? {
+                roots.add(&cert).with_context(|| ca.display().to_string())?;
+            }
+        }
+        Some(WebPkiVerifier::new(roots, None))
+    };
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(PinningVerifier {
+            webpki,
+            pins: options.tls_pin.clone(),
+        }));
+    let config = match (&options.tls_client_cert, &options.tls_client_key) {
+        (Some(cert), Some(key)) => builder
+            .with_single_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|x| anyhow!("{}: {x}", cert.display()))?,
+        _ => builder.with_no_client_auth(),
+    };
+    Ok(Some(config))
+}
+
+#[cfg(test)]
+const TEST_CERT: &str = "-----BEGIN CERTIFICATE-----
+MIIBfjCCASWgAwIBAgIUQR2h7g2i36nWAJsLHFghUBqbY/AwCgYIKoZIzj0EAwIw
+FDESMBAGA1UEAwwJbG9jYWxob3N0MCAXDTI2MTAxODIwMTY1OVoYDzIxMjYwOTI0
+MjAxNjU5WjAUMRIwEAYDVQQDDAlsb2NhbGhvc3QwWTATBgcqhkjOPQIBBggqhkjO
+PQMBBwNCAAQzlcQPzv8jGNK4eSfMInhceen8xRQQvGbtN3AjbuirsC1du/A1pfSP
+XG2n/HcOQUle5GpQssHd9aGlMj3Gnm1Uo1MwUTAdBgNVHQ4EFgQU99U1RAwr9JQa
+JfSiW4Hi4BMI/w8wHwYDVR0jBBgwFoAU99U1RAwr9JQaJfSiW4Hi4BMI/w8wDwYD
+VR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNHADBEAiBN/W2mtgmnnNU1UGn0nh5I
+4TuiKKVr+VHecK25edk8OgIgCtNLHm3MA1JsM8nsP8PM3bh4AyrrS+ZNN4u8hfrr
+Pds=
+-----END CERTIFICATE-----
+";
+
+#[test]
+fn pins() {
+    let cert = rustls_pemfile::certs(&mut TEST_CERT.as_bytes()).unwrap();
+    let cert = Certificate(cert[0].clone());
+    let verifier = |pin: &str| PinningVerifier {
+        webpki: None,
+        pins: vec![pin.parse().unwrap()],
+    };
+    let verify = |v: PinningVerifier| {
+        v.verify_server_cert(
+            &cert,
+            &[],
+            &ServerName::try_from("localhost").unwrap(),
+            &mut std::iter::empty(),
+            &[],
+            SystemTime::now(),
+        )
+        .is_ok()
+    };
+    // openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64
+    assert!(verify(verifier(
+        "sha256//O9Gzt1DjLWKY5rwGGnm01uYTokV/JKkMtBmqu25U8g8="
+    )));
+    // openssl x509 -noout -fingerprint -sha256
+    assert!(verify(verifier(
+        "9C:A7:FC:46:12:DB:8D:D0:B0:20:22:1D:3D:B8:82:96:F9:71:21:17:ED:E2:5B:28:66:9C:20:25:32:AF:97:18"
+    )));
+    assert!(!verify(verifier(
+        "sha256//AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+    )));
+    "sha256//AAAA".parse::<Pin>().unwrap_err();
+    "9C:A7".parse::<Pin>().unwrap_err();
+}
@@ -0,0 +1,156 @@
+// Datagrams travel through a session as a byte stream,
+// each one prefixed by its length as big-endian u16.
+
+use crate::close::{CloseCause, CloseReason};
+use bytes::Bytes;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+pub(crate) const MAX_DATAGRAM: usize = u16::MAX as usize;
+
+/// Buffer between a bridged socket and its session.
+const STREAM_BUFFER: usize = 64 * 1024;
+
+pub(crate) fn frame(datagram: &[u8]) -> Vec<u8> {
+    let len = u16::try_from(datagram.len()).unwrap();
+    let mut out = Vec::with_capacity(2 + datagram.len());
+    out.extend(len.to_be_bytes());
+    out.extend(datagram);
+    out
+}
+
+/// `None` at the end of the stream.
+pub(crate) async fn read_frame<R: AsyncRead + Unpin>(
+    r: &mut R,
+) -> std::io::Result<Option<Vec<u8>>> {
+    let len = match r.read_u16().await {
+        Ok(x) => x,
+        Err(x) if x.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(x) => return Err(x),
+    };
+    let mut datagram = vec![0; len.into()];
+    r.read_exact(&mut datagram).await?;
+    Ok(Some(datagram))
+}
+
+/// Entry side: datagrams of `client` arrive through `received`,
+/// the ones coming out of the session are sent back to it.
+/// Returns the stream to hand to the session.
+pub(crate) fn bridge_client(
+    socket: Arc<UdpSocket>,
+    client: SocketAddr,
+    mut received: mpsc::Receiver<Bytes>,
+) -> DuplexStream {
+    let (stream, far) = tokio::io::duplex(STREAM_BUFFER);
+    let (mut far_read, mut far_write) = tokio::io::split(far);
+    let up = async move {
+        while let Some(x) = received.recv().await {
+            far_write.write_all(&frame(&x)).await?;
+        }
+        Ok(())
+    };
+    let down = async move {
+        while let Some(x) = read_frame(&mut far_read).await? {
+            socket.send_to(&x, client).await?;
+        }
+        Ok(())
+    };
+    tokio::spawn(async move {
+        let x: std::io::Result<()> = tokio::select! {
+            x = up => x,
+            x = down => x,
+        };
+        if let Err(x) = x {
+            dbg!(x, client);
+        }
+    });
+    stream
+}
+
+/// Exit side: `target` as a stream, which ends
+/// after `idle` without a datagram in either direction.
+pub(crate) async fn connect(
+    target: &[SocketAddr],
+    idle: Option<Duration>,
+    cause: CloseCause,
+) -> std::io::Result<DuplexStream> {
+    let any = match target.first() {
+        Some(SocketAddr::V6(_)) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        _ => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    };
+    let socket = UdpSocket::bind((any, 0)).await?;
+    socket.connect(target).await?;
+
+    let (stream, far) = tokio::io::duplex(STREAM_BUFFER);
+    let (mut far_read, mut far_write) = tokio::io::split(far);
+    let last = Arc::new(Mutex::new(Instant::now()));
+    let touch = {
+        let last = last.clone();
+        move || *last.lock().unwrap() = Instant::now()
+    };
+    tokio::spawn(async move {
+        let up = async {
+            while let Some(x) = read_frame(&mut far_read).await? {
+                touch();
+                socket.send(&x).await?;
+            }
+            Ok(())
+        };
+        let down = async {
+            let mut buf = vec![0; MAX_DATAGRAM];
+            loop {
+                let len = match socket.recv(&mut buf).await {
+                    Ok(x) => x,
+                    // ICMP port unreachable of an earlier datagram
+                    Err(x) if x.kind() == ErrorKind::ConnectionRefused => continue,
+                    Err(x) => return Err(x),
+                };
+                touch();
+                far_write.write_all(&frame(&buf[..len])).await?;
+            }
+        };
+        let expire = async {
+            let Some(idle) = idle else {
+                return futures::future::pending().await;
+            };
+            loop {
+                let deadline = *last.lock().unwrap() + idle;
+                if Instant::now() >= deadline {
+                    cause.set(CloseReason::Timeout);
+                    break;
+                }
+                tokio::time::sleep_until(deadline).await;
+            }
+        };
+        let x: std::io::Result<()> = tokio::select! {
+            x = up => x,
+            x = down => x,
+            () = expire => Ok(()),
+        };
+        if let Err(x) = x {
+            dbg!(x, "udp target");
+        }
+    });
+    Ok(stream)
+}
+
+#[test]
+fn framing() {
+    tokio::runtime::Runtime::new().unwrap().block_on(async {
+        let mut stream = [frame(b"hello"), frame(b""), frame(&[7; 300])].concat();
+        stream.extend([0, 5, b'c']);
+        let mut r = stream.as_slice();
+        assert_eq!(read_frame(&mut r).await.unwrap().unwrap(), b"hello");
+        assert_eq!(read_frame(&mut r).await.unwrap().unwrap(), b"");
+        assert_eq!(read_frame(&mut r).await.unwrap().unwrap(), [7; 300]);
+        // cut off
+        read_frame(&mut r).await.unwrap_err();
+        assert_eq!(read_frame(&mut r).await.unwrap(), None);
+    });
+}
@@ -0,0 +1,132 @@
+use reqwest::{Proxy, Url};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(Clone, Debug, Default, clap::Args)]
+pub struct UpstreamProxyOptions {
+    /// Reach the exit node through this proxy, e.g. `http://proxy:3128` or `socks5h://proxy:1080`.
+    /// Proxy environment variables are ignored then.
+    #[clap(long, value_name = "URL")]
+    pub proxy: Option<Url>,
+
+    /// Credentials for --proxy.
+    #[clap(long, value_name = "USER:PASSWORD", requires = "proxy")]
+    pub proxy_auth: Option<String>,
+
+    /// Hosts reached without --proxy, comma separated: host names (matching subdomains too),
+    /// IP addresses, CIDR ranges or `*`.
+    #[clap(long, value_name = "LIST", value_delimiter = ',', requires = "proxy")]
+    pub no_proxy: Vec<NoProxy>,
+
+    /// Ignore `HTTP_PROXY`, `HTTPS_PROXY`, `ALL_PROXY` and `NO_PROXY`.
+    #[clap(long, conflicts_with = "proxy")]
+    pub no_env_proxy: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NoProxy {
+    Any,
+    Domain(String),
+    Net(IpAddr, u8),
+}
+
+impl FromStr for NoProxy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s == "*" {
+            return Ok(Self::Any);
+        }
+        if let Some((ip, len)) = s.split_once('/') {
+            let ip: IpAddr = ip.parse().map_err(|x| format!("{s:?}: {x}"))?;
+            let len: u8 = len.parse().map_err(|x| format!("{s:?}: {x}"))?;
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            if len > max {
+                return Err(format!("{s:?}: prefix longer than {max}"));
+            }
+            return Ok(Self::Net(ip, len));
+        }
+        // [::1] as in URLs
+        let host = s.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            let len = if ip.is_ipv4() { 32 } else { 128 };
+            return Ok(Self::Net(ip, len));
+        }
+        if s.is_empty() {
+            return Err("empty entry".to_owned());
+        }
+        Ok(Self::Domain(s.trim_start_matches('.').to_ascii_lowercase()))
+    }
+}
+
+impl NoProxy {
+    fn matches(&self, url: &Url) -> bool {
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        match self {
+            Self::Any => true,
+            Self::Domain(domain) => {
+                let host = host.to_ascii_lowercase();
+                host == *domain
+                    || host
+                        .strip_suffix(domain.as_str())
+                        .is_some_and(|x| x.ends_with('.'))
+            }
+            Self::Net(net, len) => {
+                let Ok(ip) = host.parse::<IpAddr>() else {
+                    return false;
+                };
+                let (ip, net, bits) = match (ip, net) {
+                    (IpAddr::V4(ip), IpAddr::V4(net)) => {
+                        (u128::from(u32::from(ip)), u128::from(u32::from(*net)), 32)
+                    }
+                    (IpAddr::V6(ip), IpAddr::V6(net)) => (u128::from(ip), u128::from(*net), 128),
+                    _ => return false,
+                };
+                let shift = bits - u32::from(*len);
+                shift == 128 || ip >> shift == net >> shift
+            }
+        }
+    }
+}
+
+/// `None` to keep reqwest's default, which reads the proxy environment variables.
+pub(crate) fn proxy(options: &UpstreamProxyOptions) -> Option<Proxy> {
+    let mut url = options.proxy.clone()?;
+    if let Some(auth) = &options.proxy_auth {
+        let (user, password) = auth.split_once(':').unwrap_or((auth, ""));
+        url.set_username(user).unwrap();
+        url.set_password(Some(password)).unwrap();
+    }
+    let no_proxy = options.no_proxy.clone();
+    Some(Proxy::custom(move |target| {
+        if no_proxy.iter().any(|x| x.matches(target)) {
+            None
+        } else {
+            Some(url.clone())
+        }
+    }))
+}
+
+#[test]
+fn no_proxy() {
+    let matches = |entry: &str, url: &str| {
+        entry
+            .parse::<NoProxy>()
+            .unwrap()
+            .matches(&url.parse().unwrap())
+    };
+    assert!(matches("*", "http://exit.example.com/"));
+    assert!(matches("example.com", "http://example.com/"));
+    assert!(matches(".example.com", "http://exit.Example.com:8080/"));
+    assert!(!matches("example.com", "http://notexample.com/"));
+    assert!(matches("10.0.0.0/8", "http://10.1.2.3/"));
+    assert!(!matches("10.0.0.0/8", "http://11.1.2.3/"));
+    assert!(matches("0.0.0.0/0", "http://11.1.2.3/"));
+    assert!(matches("::1", "http://[::1]:8080/"));
+    assert!(matches("fd00::/8", "http://[fd12::1]/"));
+    assert!(!matches("fd00::/8", "http://10.1.2.3/"));
+    "10.0.0.0/33".parse::<NoProxy>().unwrap_err();
+}